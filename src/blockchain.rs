@@ -2,176 +2,1124 @@ use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use log::{info, error};
+use libp2p::{identity, PeerId};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use super::state::State;
+use super::utxo::{UtxoSet, UtxoTransaction};
+use super::merkle::{self, MerkleProof};
+use super::pow::{self, DifficultyConfig, DIFFICULTY_BITS};
+use super::events::{ChainEvent, EventBus};
+use super::blocktree::BlockTree;
+use super::storage::{ChainStore, MemoryStore};
+use super::mempool::{Mempool, MempoolError};
+use std::io;
+use std::fmt;
 
 
-const DIFFICULTY: &str = "00";
+pub const BLOCK_VERSION: u32 = 1;
+pub const HEADER_SIZE: usize = 92;
+pub const BLOCK_REWARD: u64 = 50;
+pub const COINBASE_SENDER: &str = "coinbase";
+pub const MEDIAN_TIME_SPAN: usize = 11; // blocks whose median timestamp a new block must exceed
+pub const MAX_FUTURE_DRIFT: i64 = 10 * 60; // seconds a block may be ahead of our clock
 
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Block {
-    pub id: u32,
-    pub timestamp: i64,
-    pub header: String,
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlockHeader {
+    pub version: u32,
+    pub height: u32,
     pub prev_hash: String,
-    pub transactions: Vec<Transaction>,
+    pub merkle_root: String, // commits to the hashes of `transactions` and `utxo_transactions`
+    pub timestamp: i64,
+    pub difficulty_target: u32, // compact encoding of the proof-of-work target
     pub nonce: u64,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Transaction {}
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Block {
+    pub hash: String, // hash of `header`, identifies the block
+    pub header: BlockHeader,
+    pub transactions: Vec<Transaction>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub utxo_transactions: Vec<UtxoTransaction>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Transaction {
+    pub sender: String,
+    pub recipient: String,
+    pub amount: u64,
+    pub fee: u64,
+    pub nonce: u64, // per-sender counter, stops the same transfer being replayed
+    pub public_key: String, // hex protobuf encoding of the sender's libp2p public key
+    pub signature: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LedgerMode {
+    Account, // blocks carry `transactions`, tracked in `State`
+    Utxo, // blocks carry `utxo_transactions`, tracked in `UtxoSet`
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ChainError {
+    Empty,
+    WrongGenesis,
+    InvalidBlock(u32),
+    Ledger { height: u32, reason: String },
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChainError::Empty => write!(f, "chain is empty"),
+            ChainError::WrongGenesis => write!(f, "chain starts from a different genesis block"),
+            ChainError::InvalidBlock(height) => write!(f, "block {} is invalid", height),
+            ChainError::Ledger { height, reason } => write!(f, "block {} rejected by ledger: {}", height, reason),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BlockStatus {
+    Extended, // appended to the active chain
+    Reorg, // completed a heavier branch, which became the active chain
+    SideChain, // stored on a branch that doesn't outweigh the active chain (yet)
+    Orphan, // parent unknown, held until it arrives
+    Known,
+    Invalid,
+}
 
 pub struct App {
-    pub blocks: Vec<Block>,
+    pub store: Box<dyn ChainStore>, // active chain, and every block added to `tree`
+    pub mode: LedgerMode,
+    pub difficulty: DifficultyConfig,
+    pub state: State, // balances and nonces at the tip of the active chain
+    pub utxos: UtxoSet, // unspent outputs at the tip of the active chain
+    pub events: EventBus,
+    pub tree: BlockTree, // every known block, side branches and orphans included
+    pub mempool: Mempool, // account mode transactions waiting for a block
+    tx_index: HashMap<String, (u32, usize)>, // active chain transactions: block height and position
 }
 
 impl App {
+    #[cfg(test)]
     pub fn new() -> App {
+        App::with_mode(LedgerMode::Account)
+    }
+
+    // Keeps blocks in memory only; the genesis block still has to be added.
+    pub fn with_mode(mode: LedgerMode) -> App {
         App {
-            blocks: vec![]
+            store: Box::new(MemoryStore::new()),
+            mode,
+            difficulty: DifficultyConfig::default(),
+            state: State::new(),
+            utxos: UtxoSet::new(),
+            events: EventBus::default(),
+            tree: BlockTree::new(),
+            mempool: Mempool::default(),
+            tx_index: HashMap::new(),
+        }
+    }
+
+    // Starts from genesis and replays, re-validating, every block already in `store`.
+    pub fn with_store(mode: LedgerMode, difficulty: DifficultyConfig, mut store: Box<dyn ChainStore>) -> io::Result<App> {
+        let blocks = store.stored_blocks()?;
+        let mut app = App { store, difficulty, ..App::with_mode(mode) };
+        app.add_genesis_block();
+
+        let genesis = Block::genesis_block();
+        let mut rejected = 0;
+        for block in blocks {
+            if block.hash == genesis.hash {
+                continue;
+            }
+            if app.add_block_to_chain(block) == BlockStatus::Invalid {
+                rejected += 1;
+            }
+        }
+        if rejected > 0 {
+            error!("{} stored blocks failed validation and were skipped", rejected);
+        }
+        info!("Loaded chain from store, height {}", app.latest_block().header.height);
+        Ok(app)
+    }
+
+    // Active chain, genesis first.
+    pub fn blocks(&self) -> &[Block] {
+        self.store.chain()
+    }
+
+    pub fn latest_block(&self) -> &Block {
+        self.blocks().last().expect("there must be at least one block")
+    }
+
+    fn persist(&mut self, block: &Block) {
+        if let Err(e) = self.store.store(block) {
+            error!("Could not persist block {}: {}", block.hash, e);
+        }
+    }
+
+    fn index_transactions(&mut self, block: &Block) {
+        for (index, hash) in block.transaction_hashes().into_iter().enumerate() {
+            self.tx_index.insert(hash, (block.header.height, index));
+        }
+    }
+
+    // The active chain block holding transaction `hash`, and its position in that block.
+    pub fn locate_transaction(&self, hash: &str) -> Option<(&Block, usize)> {
+        let (height, index) = *self.tx_index.get(hash)?;
+        Some((self.blocks().get(height as usize)?, index))
+    }
+
+    fn push_block(&mut self, block: Block) {
+        self.index_transactions(&block);
+        let hash = block.hash.clone();
+        if let Err(e) = self.store.push(block) {
+            error!("Could not persist block {}: {}", hash, e);
         }
     }
 
     pub fn add_genesis_block(&mut self) {
-        self.blocks.push(Block::genesis_block())
+        let genesis = Block::genesis_block();
+        self.tree.insert(genesis.clone());
+        self.push_block(genesis)
     }
 
-    pub fn add_block_to_chain(&mut self, block: Block) { 
-        let latest_block = self.blocks.last().unwrap();
-        if self.check_block_is_valid(latest_block, &block) {
-            self.blocks.push(block)
-        } else {
-            error!("Received invalid block");
+    // Returns what happened to `block`; orphans it completes are connected as well.
+    pub fn add_block_to_chain(&mut self, block: Block) -> BlockStatus { 
+        let mut status = None;
+        let mut pending = vec![block];
+        while let Some(block) = pending.pop() {
+            let hash = block.hash.clone();
+            let result = self.insert_block(block);
+            status.get_or_insert(result);
+
+            if matches!(result, BlockStatus::Extended | BlockStatus::Reorg | BlockStatus::SideChain) {
+                pending.extend(self.tree.take_orphans_of(&hash));
+            }
         }
+
+        status.expect("at least one block was inserted")
     }
 
-    fn check_block_is_valid(&self, latest_block: &Block, new_block: &Block) -> bool {  
-        if latest_block.id + 1 != new_block.id {
-            error!("Invalid block ID!");
-            return false;
-        } else if new_block.timestamp <= latest_block.timestamp {
-            error!("Invalid block timestamp!");
-            return false;
-        } else if new_block.header != hex::encode(calculate_hash(
-            &new_block.id, 
-            &new_block.timestamp, 
-            &new_block.prev_hash, 
-            &new_block.transactions, 
-            &new_block.nonce
-        )) {
-            error!("Invalid block header!");
-            return false;
-        } else if new_block.prev_hash != latest_block.header {
-            error!("Previous hash doesn't match!");
-            return false; 
-        } else if &new_block.header[0..=DIFFICULTY.len()] != DIFFICULTY {
-            error!("Difficulty does not match!");
-            return false;
-        } else {
-            return true;
+    fn insert_block(&mut self, block: Block) -> BlockStatus {
+        if self.tree.contains(&block.hash) {
+            return BlockStatus::Known;
+        }
+
+        let tip = self.latest_block().hash.clone();
+        if block.header.prev_hash == tip {
+            if !self.check_block_is_valid(self.blocks(), &block) {
+                error!("Received invalid block");
+                return BlockStatus::Invalid;
+            }
+            if let Err(e) = self.apply_to_ledger(&block) {
+                error!("Block {} rejected by ledger: {}", block.header.height, e);
+                return BlockStatus::Invalid;
+            }
+
+            self.mempool.remove_included(&block);
+            self.mempool.revalidate(&self.state);
+            self.tree.insert(block.clone());
+            self.events.emit(ChainEvent::new_head(&block));
+            self.push_block(block);
+            return BlockStatus::Extended;
+        }
+
+        if self.tree.get(&block.header.prev_hash).is_none() {
+            // only blocks that cost real work may take up space in the pool, and only with the
+            // body their header commits to, or a tampered copy would shut out the real block
+            if !block.has_valid_proof_of_work() || block.header.merkle_root != block.compute_merkle_root() {
+                error!("Received invalid orphan block");
+                return BlockStatus::Invalid;
+            }
+            info!("Block {} is an orphan, waiting for {}", block.hash, block.header.prev_hash);
+            self.tree.add_orphan(block);
+            return BlockStatus::Orphan;
+        }
+
+        let parent_chain = self.tree.chain_to(&block.header.prev_hash);
+        if !self.check_block_is_valid(&parent_chain, &block) {
+            error!("Received invalid side chain block");
+            return BlockStatus::Invalid;
+        }
+
+        let hash = block.hash.clone();
+        self.persist(&block);
+        self.tree.insert(block);
+        if self.tree.work(&hash) <= self.tree.work(&tip) {
+            info!("Stored side chain block {}", hash);
+            return BlockStatus::SideChain;
+        }
+
+        let candidate = self.tree.chain_to(&hash);
+        match self.check_chain_is_valid(&candidate) {
+            Ok(()) => {
+                self.replace_chain(candidate);
+                BlockStatus::Reorg
+            }
+            Err(e) => {
+                error!("Heavier branch is invalid: {}", e);
+                self.tree.remove_branch(&hash);
+                BlockStatus::Invalid
+            }
         }
     }
 
-    pub fn choose_chain(&mut self, local_chain: Vec<Block>, new_chain: Vec<Block>) -> Vec<Block> {
-        let is_local_valid = self.check_chain_is_valid(&local_chain);
-        let is_new_valid = self.check_chain_is_valid(&new_chain);
+    fn apply_to_ledger(&mut self, block: &Block) -> Result<(), String> {
+        match self.mode {
+            LedgerMode::Account if !block.utxo_transactions.is_empty() => {
+                Err(String::from("utxo transactions in an account mode block"))
+            }
+            LedgerMode::Utxo if !block.transactions.is_empty() => {
+                Err(String::from("account transactions in a utxo mode block"))
+            }
+            LedgerMode::Account => self.state.apply_block(block).map_err(|e| e.to_string()),
+            LedgerMode::Utxo => self.utxos.apply_block(block).map_err(|e| e.to_string()),
+        }
+    }
 
-        if is_local_valid && is_new_valid {
-            if new_chain.len() <= local_chain.len() {
-                local_chain
-            } else {
-                new_chain
+    // Switches to `blocks`, which must already be valid. The ledger is rolled back to the
+    // last block both chains share and the new branch is applied on top of it.
+    pub fn replace_chain(&mut self, blocks: Vec<Block>) {
+        for block in &blocks {
+            if self.tree.get(&block.hash).is_none() {
+                self.tree.insert(block.clone());
+                self.persist(block);
             }
-        } else if is_local_valid && !is_new_valid {
-            local_chain
-        } else if !is_local_valid && is_new_valid {
-            new_chain
-        } else {
-            panic!("both local and received chains are invalid!");
+        }
+
+        let fork = self
+            .blocks()
+            .iter()
+            .zip(blocks.iter())
+            .take_while(|(local, new)| local.hash == new.hash)
+            .count();
+        if fork == self.blocks().len() && fork == blocks.len() {
+            return;
+        }
+
+        let mut ledger = App::with_mode(self.mode);
+        for block in &blocks {
+            ledger.apply_to_ledger(block).expect("replacement chain must be valid");
+        }
+
+        let disconnected: Vec<String> = self.blocks()[fork..].iter().map(|b| b.hash.clone()).collect();
+        let connected: Vec<String> = blocks[fork..].iter().map(|b| b.hash.clone()).collect();
+        let new_heads: Vec<ChainEvent> = blocks[fork..].iter().map(ChainEvent::new_head).collect();
+        let old_tip = self.blocks().last().map(|b| b.hash.clone()).unwrap_or_default();
+
+        for block in &self.store.chain()[fork..] {
+            for hash in block.transaction_hashes() {
+                self.tx_index.remove(&hash);
+            }
+        }
+        for block in &blocks[fork..] {
+            self.index_transactions(block);
+        }
+
+        self.state = ledger.state;
+        self.utxos = ledger.utxos;
+        self.mempool.revalidate(&self.state);
+        if let Err(e) = self.store.set_chain(blocks) {
+            error!("Could not persist new chain: {}", e);
+        }
+
+        if !disconnected.is_empty() {
+            info!("Reorg at height {}: {} blocks rolled back, {} applied", fork, disconnected.len(), connected.len());
+            self.events.emit(ChainEvent::Reorg {
+                fork_height: fork as u32,
+                old_tip,
+                new_tip: self.latest_block().hash.clone(),
+                disconnected,
+                connected,
+            });
+        }
+        for event in new_heads {
+            self.events.emit(event);
         }
     }
 
-    fn check_chain_is_valid(&self, chain: &Vec<Block>) -> bool {
-        for i in 0..chain.len() {
-            if i == 0 {
-                continue;
+    pub fn balance(&self, address: &str) -> u64 {
+        match self.mode {
+            LedgerMode::Account => self.state.balance(address),
+            LedgerMode::Utxo => self.utxos.balance(address),
+        }
+    }
+
+    // Hashes of the active chain going back from the tip, one by one at first and then with
+    // doubling gaps, always ending at genesis. A peer finds where our chains part with it.
+    pub fn locator(&self) -> Vec<String> {
+        let blocks = self.blocks();
+        let mut locator = vec![];
+        let mut height = blocks.len() - 1;
+        let mut step = 1;
+        loop {
+            locator.push(blocks[height].hash.clone());
+            if height == 0 {
+                break;
             }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+
+        locator
+    }
+
+    // Headers of the active chain after the first `locator` hash on it, or after genesis.
+    pub fn headers_after(&self, locator: &[String], limit: usize) -> Vec<BlockHeader> {
+        let start = locator
+            .iter()
+            .find_map(|hash| self.blocks().iter().position(|block| &block.hash == hash))
+            .unwrap_or(0);
+
+        self.blocks()
+            .iter()
+            .skip(start + 1)
+            .take(limit)
+            .map(|block| block.header.clone())
+            .collect()
+    }
+
+    pub fn next_difficulty_target(&self) -> u32 {
+        pow::next_bits(self.blocks(), &self.difficulty)
+    }
+
+    // Counts transactions still waiting in the mempool.
+    pub fn next_nonce(&self, sender: &str) -> u64 {
+        self.state.nonce(sender) + self.mempool.pending_count(sender) as u64
+    }
+
+    pub fn submit_transaction(&mut self, tx: Transaction) -> Result<String, MempoolError> {
+        let hash = self.mempool.add(tx, &self.state)?;
+        self.events.emit(ChainEvent::PendingTransaction { hash: hash.clone() });
+        Ok(hash)
+    }
+
+    // Replays the active chain up to `height`; None if the chain isn't that long.
+    pub fn balance_at(&self, address: &str, height: u32) -> Option<u64> {
+        let end = height as usize + 1;
+        if end > self.blocks().len() {
+            return None;
+        }
+
+        let mut ledger = App::with_mode(self.mode);
+        for block in &self.blocks()[..end] {
+            ledger.apply_to_ledger(block).ok()?;
+        }
+
+        Some(ledger.balance(address))
+    }
+
+    // `chain` holds every block before `new_block`.
+    fn check_block_is_valid(&self, chain: &[Block], new_block: &Block) -> bool {  
+        let latest_block = chain.last().expect("there must be at least one block");
+        if latest_block.header.height + 1 != new_block.header.height {
+            error!("Invalid block height!");
+            false
+        } else if new_block.header.version > BLOCK_VERSION {
+            error!("Unsupported block version!");
+            false
+        } else if new_block.header.timestamp <= median_time_past(chain) {
+            error!("Block timestamp is not after the median of recent blocks!");
+            false
+        } else if new_block.header.timestamp > Utc::now().timestamp() + MAX_FUTURE_DRIFT {
+            error!("Block timestamp is too far in the future!");
+            false
+        } else if new_block.header.merkle_root != new_block.compute_merkle_root() {
+            error!("Merkle root doesn't match transactions!");
+            false
+        } else if new_block.hash != hex::encode(calculate_hash(&new_block.header)) {
+            error!("Invalid block hash!");
+            false
+        } else if new_block.header.prev_hash != latest_block.hash {
+            error!("Previous hash doesn't match!");
+            false
+        } else if !check_transactions_are_valid(&new_block.transactions)
+            || !check_utxo_transactions_are_valid(&new_block.utxo_transactions, new_block.header.height)
+        {
+            error!("Invalid transactions in block!");
+            false
+        } else if new_block.header.difficulty_target != pow::next_bits(chain, &self.difficulty) {
+            error!("Unexpected difficulty target!");
+            false
+        } else if !pow::meets_target(&hex::decode(&new_block.hash).unwrap_or_default(), new_block.header.difficulty_target) {
+            error!("Block hash does not meet the difficulty target!");
+            false
+        } else {
+            true
+        }
+    }
 
-            let first = chain.get(i - 1).expect("prev block must exist");
+    pub fn check_chain_is_valid(&self, chain: &[Block]) -> Result<(), ChainError> {
+        let genesis = chain.first().ok_or(ChainError::Empty)?;
+        if genesis.hash != Block::genesis_block().hash {
+            return Err(ChainError::WrongGenesis);
+        }
+
+        for i in 1..chain.len() {
             let second = chain.get(i).expect("latest block must exist");
 
-            if !self.check_block_is_valid(second, first) {
-                return false;
+            if !self.check_block_is_valid(&chain[..i], second) {
+                return Err(ChainError::InvalidBlock(second.header.height));
             }
         }
 
-        true
+        let mut ledger = App::with_mode(self.mode);
+        for block in chain {
+            ledger.apply_to_ledger(block).map_err(|reason| ChainError::Ledger {
+                height: block.header.height,
+                reason,
+            })?;
+        }
+
+        Ok(())
     }
 }
 
 impl Block {
     pub fn genesis_block() -> Block {
-        Block {
-            id: 0,
+        let header = BlockHeader {
+            version: BLOCK_VERSION,
+            height: 0,
+            prev_hash: hex::encode([0u8; 32]),
+            merkle_root: merkle::merkle_root(&[]),
             timestamp: 0,
-            header: String::from("genesis"),
-            prev_hash: String::from("genesis"),
+            difficulty_target: DIFFICULTY_BITS,
+            nonce: 0,
+        };
+
+        Block {
+            hash: header.hash(),
+            header,
             transactions: vec![],
-            nonce: 0
+            utxo_transactions: vec![],
         }
     }
 
-    pub fn new(id: u32, prev_hash: String, transactions: Vec<Transaction>) -> Block {
-        let timestamp = Utc::now().timestamp();
-        let (nonce, header) = mine_block(&id, &timestamp, &prev_hash, &transactions);
-        let block = Block {
-            id,
-            timestamp,
-            header,
-            prev_hash,
+    // Mined on the spot, for tests that need a valid block right away.
+    #[cfg(test)]
+    pub fn new(parent: &Block, difficulty_target: u32, transactions: Vec<Transaction>) -> Block {
+        let mut block = Block::template(parent, difficulty_target, transactions, vec![]);
+        block.hash = mine_block(&mut block.header);
+        block
+    }
+
+    // The block before its proof of work: no hash yet and a nonce of 0. Stamped a second after
+    // the parent if that's later than our clock, so blocks mined within the same second still
+    // pass the median time check.
+    pub fn template(
+        parent: &Block,
+        difficulty_target: u32,
+        transactions: Vec<Transaction>,
+        utxo_transactions: Vec<UtxoTransaction>,
+    ) -> Block {
+        let mut block = Block {
+            hash: String::new(),
+            header: BlockHeader {
+                version: BLOCK_VERSION,
+                height: parent.header.height + 1,
+                prev_hash: parent.hash.clone(),
+                merkle_root: String::new(),
+                timestamp: Utc::now().timestamp().max(parent.header.timestamp + 1),
+                difficulty_target,
+                nonce: 0,
+            },
             transactions,
-            nonce,
+            utxo_transactions,
         };
+        block.header.merkle_root = block.compute_merkle_root();
 
         block
     }
+
+    // Checked without the parent, so against the easiest target any block may have rather than
+    // the one it should have; a block claiming an easier target would cost nothing.
+    pub fn has_valid_proof_of_work(&self) -> bool {
+        self.hash == self.header.hash()
+            && pow::within_limit(self.header.difficulty_target)
+            && pow::meets_target(&hex::decode(&self.hash).unwrap_or_default(), self.header.difficulty_target)
+    }
+
+    pub fn transaction_hashes(&self) -> Vec<String> {
+        self.transactions
+            .iter()
+            .map(|tx| tx.hash())
+            .chain(self.utxo_transactions.iter().map(|tx| tx.hash()))
+            .collect()
+    }
+
+    pub fn compute_merkle_root(&self) -> String {
+        merkle::merkle_root(&self.transaction_hashes())
+    }
+
+    pub fn prove_transaction(&self, tx_hash: &str) -> Option<MerkleProof> {
+        let hashes = self.transaction_hashes();
+        let index = hashes.iter().position(|hash| hash == tx_hash)?;
+        merkle::merkle_proof(&hashes, index)
+    }
 }
 
-pub fn calculate_hash(id: &u32, timestamp: &i64, prev_hash: &String, transactions: &Vec<Transaction>, nonce: &u64) -> Vec<u8> {
-    let data = serde_json::json!({
-        "id": id,
-        "timestamp": timestamp,
-        "prev_hash": prev_hash,
-        "transactions": transactions,
-        "nonce": nonce,
-    });
+// Fixed little-endian layout, so headers can be hashed and synced without their bodies.
+impl BlockHeader {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE);
+        bytes.extend_from_slice(&self.version.to_le_bytes());
+        bytes.extend_from_slice(&self.height.to_le_bytes());
+        bytes.extend_from_slice(&hash_to_bytes(&self.prev_hash));
+        bytes.extend_from_slice(&hash_to_bytes(&self.merkle_root));
+        bytes.extend_from_slice(&self.timestamp.to_le_bytes());
+        bytes.extend_from_slice(&self.difficulty_target.to_le_bytes());
+        bytes.extend_from_slice(&self.nonce.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<BlockHeader> {
+        if bytes.len() != HEADER_SIZE {
+            return None;
+        }
 
+        Some(BlockHeader {
+            version: u32::from_le_bytes(bytes[0..4].try_into().ok()?),
+            height: u32::from_le_bytes(bytes[4..8].try_into().ok()?),
+            prev_hash: hex::encode(&bytes[8..40]),
+            merkle_root: hex::encode(&bytes[40..72]),
+            timestamp: i64::from_le_bytes(bytes[72..80].try_into().ok()?),
+            difficulty_target: u32::from_le_bytes(bytes[80..84].try_into().ok()?),
+            nonce: u64::from_le_bytes(bytes[84..92].try_into().ok()?),
+        })
+    }
+
+    pub fn hash(&self) -> String {
+        hex::encode(calculate_hash(self))
+    }
+}
+
+// Malformed hex encodes as zeros; such a header never matches its parent or body anyway.
+fn hash_to_bytes(hash: &str) -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    if let Ok(decoded) = hex::decode(hash) {
+        if decoded.len() == 32 {
+            bytes.copy_from_slice(&decoded);
+        }
+    }
+
+    bytes
+}
+
+impl Transaction {
+    pub fn new(sender: String, recipient: String, amount: u64, fee: u64, nonce: u64) -> Transaction {
+        Transaction {
+            sender,
+            recipient,
+            amount,
+            fee,
+            nonce,
+            public_key: String::new(),
+            signature: String::new(),
+        }
+    }
+
+    pub fn coinbase(recipient: String, amount: u64, height: u32) -> Transaction {
+        // the height keeps otherwise identical rewards from hashing the same
+        Transaction::new(String::from(COINBASE_SENDER), recipient, amount, 0, height as u64)
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.sender == COINBASE_SENDER
+    }
+
+    // Everything except the signature itself is covered by the signature.
+    fn signing_payload(&self) -> Vec<u8> {
+        serde_json::json!({
+            "sender": self.sender,
+            "recipient": self.recipient,
+            "amount": self.amount,
+            "fee": self.fee,
+            "nonce": self.nonce,
+            "public_key": self.public_key,
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    pub fn sign(&mut self, keys: &identity::Keypair) {
+        self.public_key = hex::encode(keys.public().into_protobuf_encoding());
+        let signature = keys.sign(&self.signing_payload()).expect("can sign transaction");
+        self.signature = hex::encode(signature);
+    }
+
+    pub fn verify_signature(&self) -> bool {
+        verify_signed_by(&self.public_key, &self.signature, &self.signing_payload(), &self.sender)
+    }
+
+    pub fn hash(&self) -> String {
+        let data = serde_json::to_string(self).expect("can jsonify transaction");
+
+        let mut hasher = Sha256::new();
+        hasher.update(data.as_bytes());
+        hex::encode(hasher.finalize().as_slice())
+    }
+
+    pub fn is_well_formed(&self) -> bool {
+        !self.sender.is_empty()
+            && !self.recipient.is_empty()
+            && self.sender != self.recipient
+            && self.amount > 0
+    }
+}
+
+// Checks a hex signature over `payload` and that the hex public key belongs to `address`.
+pub fn verify_signed_by(public_key: &str, signature: &str, payload: &[u8], address: &str) -> bool {
+    let public_key = match hex::decode(public_key)
+        .ok()
+        .and_then(|bytes| identity::PublicKey::from_protobuf_encoding(&bytes).ok())
+    {
+        Some(public_key) => public_key,
+        None => return false,
+    };
+    let signature = match hex::decode(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+
+    // an address is the peer id derived from the signing key
+    PeerId::from(public_key.clone()).to_string() == address
+        && public_key.verify(payload, &signature)
+}
+
+fn check_transactions_are_valid(transactions: &[Transaction]) -> bool {
+    let mut seen = HashSet::new();
+    for (i, tx) in transactions.iter().enumerate() {
+        if !tx.is_well_formed() {
+            error!("Malformed transaction from {}", tx.sender);
+            return false;
+        }
+        if tx.is_coinbase() {
+            if i != 0 {
+                error!("Coinbase must be the first transaction in a block");
+                return false;
+            }
+            continue;
+        }
+        if !tx.verify_signature() {
+            error!("Invalid signature on transaction from {}", tx.sender);
+            return false;
+        }
+        if !seen.insert((tx.sender.as_str(), tx.nonce)) {
+            error!("Duplicate nonce {} from {} in block", tx.nonce, tx.sender);
+            return false;
+        }
+    }
+
+    true
+}
+
+// Signatures need the spent outputs, so those are checked when the block is applied to the `UtxoSet`.
+fn check_utxo_transactions_are_valid(transactions: &[UtxoTransaction], height: u32) -> bool {
+    let mut spent = HashSet::new();
+    for (i, tx) in transactions.iter().enumerate() {
+        if tx.outputs.is_empty()
+            || tx.outputs.iter().any(|out| out.amount == 0 || out.recipient.is_empty())
+            || tx.total_output().is_none()
+        {
+            error!("Malformed utxo transaction {}", tx.hash());
+            return false;
+        }
+        if tx.is_coinbase() {
+            if i != 0 {
+                error!("Coinbase must be the first transaction in a block");
+                return false;
+            }
+            // an earlier height would repeat an earlier coinbase's hash, and with it its outpoint
+            if tx.coinbase_height != Some(height) {
+                error!("Coinbase is for height {:?}, not {}", tx.coinbase_height, height);
+                return false;
+            }
+            continue;
+        }
+        if !tx.inputs.iter().all(|input| spent.insert(&input.prev_out)) {
+            error!("Double spend within block in {}", tx.hash());
+            return false;
+        }
+    }
+
+    true
+}
+
+// Median timestamp of the last `MEDIAN_TIME_SPAN` blocks. New blocks only have to be later
+// than this, not than their parent, so one block stamped far ahead doesn't hold back the rest.
+pub fn median_time_past(chain: &[Block]) -> i64 {
+    let mut timestamps: Vec<i64> = chain.iter().rev().take(MEDIAN_TIME_SPAN).map(|block| block.header.timestamp).collect();
+    timestamps.sort_unstable();
+    timestamps.get(timestamps.len() / 2).copied().unwrap_or(0)
+}
+
+pub fn calculate_hash(header: &BlockHeader) -> Vec<u8> {
     let mut hasher = Sha256::new();
-    hasher.update(data.to_string().as_bytes());
+    hasher.update(header.to_bytes());
     hasher.finalize().as_slice().to_owned()
 }
 
-fn mine_block(id: &u32, timestamp: &i64, prev_hash: &String, transactions: &Vec<Transaction>) -> (u64, String) {
-    let mut nonce = 0;
-
-    loop {
-        nonce += 1;
+// Mines on the calling thread; the node itself only mines through `miner::Miner`.
+#[cfg(test)]
+pub fn mine_block(header: &mut BlockHeader) -> String {
+    mine_block_until(header, &AtomicBool::new(false)).expect("mining is never cancelled")
+}
 
-        let result = calculate_hash(id, timestamp, prev_hash, transactions, &nonce);
+// Tries nonces until the hash meets the target, or gives up once `cancelled` is set.
+pub fn mine_block_until(header: &mut BlockHeader, cancelled: &AtomicBool) -> Option<String> {
+    while !cancelled.load(Ordering::Relaxed) {
+        header.nonce += 1;
 
-        let r = &result[0..DIFFICULTY.len()]
-            .into_iter()
-            .map(|n| n.to_string())
-            .collect::<String>();
+        let result = calculate_hash(header);
 
-        if r == DIFFICULTY {
-            info!("Mined a new block with ID {}", id);
-            return (nonce, hex::encode(result))
+        if pow::meets_target(&result, header.difficulty_target) {
+            info!("Mined a new block at height {}", header.height);
+            return Some(hex::encode(result));
         }
     }
+
+    None
 }
 
 #[cfg(test)]
-mod tests {
+pub mod tests {
+    use super::*;
+
     #[test]
     fn test() {}
+
+    fn signed_transaction(keys: &identity::Keypair) -> Transaction {
+        let sender = PeerId::from(keys.public()).to_string();
+        let mut tx = Transaction::new(sender, String::from("recipient"), 10, 1, 0);
+        tx.sign(keys);
+        tx
+    }
+
+    #[test]
+    fn transaction_inclusion_can_be_proven() {
+        let keys = identity::Keypair::generate_ed25519();
+        let tx = signed_transaction(&keys);
+        let mut block = Block::genesis_block();
+        block.transactions = vec![Transaction::coinbase(String::from("miner"), BLOCK_REWARD, 1), tx.clone()];
+        block.header.merkle_root = block.compute_merkle_root();
+
+        let proof = block.prove_transaction(&tx.hash()).expect("transaction is in block");
+        assert!(merkle::verify_proof(&block.header.merkle_root, &proof));
+        assert!(block.prove_transaction("missing").is_none());
+    }
+
+    fn mine_after(parent: &Block, transactions: Vec<Transaction>) -> Block {
+        Block::new(parent, DIFFICULTY_BITS, transactions)
+    }
+
+    fn mine_at(parent: &Block, timestamp: i64) -> Block {
+        let mut block = mine_after(parent, vec![]);
+        block.header.timestamp = timestamp;
+        block.hash = mine_block(&mut block.header);
+        block
+    }
+
+    fn mine_on(app: &App, transactions: Vec<Transaction>) -> Block {
+        Block::new(app.latest_block(), app.next_difficulty_target(), transactions)
+    }
+
+    fn reward(miner: &str, height: u32) -> Vec<Transaction> {
+        vec![Transaction::coinbase(String::from(miner), BLOCK_REWARD, height)]
+    }
+
+    // A chain of `count` blocks after genesis, shared by the API and sync tests. The blocks are
+    // exactly on schedule, so the difficulty never rises.
+    pub fn app_with_blocks(count: u32) -> App {
+        let mut app = App::new();
+        app.add_genesis_block();
+        for height in 1..=count {
+            let latest_block = app.latest_block();
+            let mut block = Block::template(latest_block, app.next_difficulty_target(), reward("miner", height), vec![]);
+            block.header.timestamp = latest_block.header.timestamp + app.difficulty.target_block_time;
+            block.hash = mine_block(&mut block.header);
+            assert_eq!(app.add_block_to_chain(block), BlockStatus::Extended);
+        }
+        app
+    }
+
+    #[test]
+    fn mined_block_passes_validation() {
+        let mut app = App::new();
+        app.add_genesis_block();
+
+        let block = Block::new(
+            app.latest_block(),
+            app.next_difficulty_target(),
+            vec![Transaction::coinbase(String::from("miner"), BLOCK_REWARD, 1)],
+        );
+        assert!(app.check_block_is_valid(app.blocks(), &block));
+        assert_eq!(app.add_block_to_chain(block), BlockStatus::Extended);
+
+        // on a parent mined moments ago, possibly within the same second
+        let block = Block::new(
+            app.latest_block(),
+            app.next_difficulty_target(),
+            vec![Transaction::coinbase(String::from("miner"), BLOCK_REWARD, 2)],
+        );
+        assert!(app.check_block_is_valid(app.blocks(), &block));
+        assert_eq!(app.add_block_to_chain(block), BlockStatus::Extended);
+        assert_eq!(app.check_chain_is_valid(app.blocks()), Ok(()));
+    }
+
+    #[test]
+    fn cancelled_mining_gives_up() {
+        let mut header = Block::template(&Block::genesis_block(), DIFFICULTY_BITS, vec![], vec![]).header;
+        assert_eq!(mine_block_until(&mut header, &AtomicBool::new(true)), None);
+    }
+
+    #[test]
+    fn balance_is_available_at_past_heights() {
+        let mut app = App::new();
+        app.add_genesis_block();
+        for height in 1..=2 {
+            let block = mine_on(&app, reward("miner", height));
+            assert_eq!(app.add_block_to_chain(block), BlockStatus::Extended);
+        }
+
+        assert_eq!(app.balance_at("miner", 0), Some(0));
+        assert_eq!(app.balance_at("miner", 1), Some(BLOCK_REWARD));
+        assert_eq!(app.balance_at("miner", 2), Some(app.balance("miner")));
+        assert_eq!(app.balance_at("miner", 3), None);
+    }
+
+    #[test]
+    fn heavier_fork_wins_and_reorg_is_reported() {
+        let mut app = App::new();
+        app.add_genesis_block();
+        let mut events = app.events.subscribe();
+
+        let mut other = App::new();
+        other.add_genesis_block();
+        for _ in 0..2 {
+            let height = other.blocks().len() as u32;
+            let block = mine_on(&other, vec![Transaction::coinbase(String::from("other"), BLOCK_REWARD, height)]);
+            assert_eq!(other.add_block_to_chain(block), BlockStatus::Extended);
+        }
+
+        let block = mine_on(&app, vec![Transaction::coinbase(String::from("local"), BLOCK_REWARD, 1)]);
+        assert_eq!(app.add_block_to_chain(block), BlockStatus::Extended);
+        let local_tip = app.blocks()[1].hash.clone();
+
+        // as much work as ours keeps our chain, more switches to it
+        assert_eq!(app.add_block_to_chain(other.blocks()[1].clone()), BlockStatus::SideChain);
+        assert_eq!(app.add_block_to_chain(other.blocks()[2].clone()), BlockStatus::Reorg);
+        assert_eq!(app.blocks().len(), 3);
+
+        assert_eq!(app.state.balance("local"), 0);
+        assert_eq!(app.state.balance("other"), 2 * BLOCK_REWARD);
+        let local_coinbase = Transaction::coinbase(String::from("local"), BLOCK_REWARD, 1).hash();
+        assert!(app.locate_transaction(&local_coinbase).is_none());
+        let other_coinbase = Transaction::coinbase(String::from("other"), BLOCK_REWARD, 2).hash();
+        assert_eq!(app.locate_transaction(&other_coinbase).map(|(block, index)| (block.header.height, index)), Some((2, 0)));
+        let events: Vec<ChainEvent> = std::iter::from_fn(|| events.try_recv().ok()).collect();
+        let topics: Vec<&str> = events.iter().map(|event| event.topic()).collect();
+        assert_eq!(topics, vec!["newHeads", "reorg", "newHeads", "newHeads"]);
+        match &events[1] {
+            ChainEvent::Reorg { fork_height, disconnected, connected, .. } => {
+                assert_eq!(*fork_height, 1);
+                assert_eq!(disconnected, &vec![local_tip]);
+                assert_eq!(connected.len(), 2);
+            }
+            event => panic!("expected a reorg, got {:?}", event),
+        }
+    }
+
+    #[test]
+    fn side_branch_is_kept_until_it_outweighs_the_active_chain() {
+        let mut app = App::new();
+        app.add_genesis_block();
+        let genesis = app.blocks()[0].clone();
+
+        let main = mine_on(&app, reward("main", 1));
+        assert_eq!(app.add_block_to_chain(main.clone()), BlockStatus::Extended);
+
+        let side = mine_after(&genesis, reward("side", 1));
+        assert_eq!(app.add_block_to_chain(side.clone()), BlockStatus::SideChain);
+        assert_eq!(app.blocks().last().map(|b| &b.hash), Some(&main.hash));
+        assert_eq!(app.tree.tips().len(), 2);
+
+        let side_tip = mine_after(&side, reward("side", 2));
+        assert_eq!(app.add_block_to_chain(side_tip.clone()), BlockStatus::Reorg);
+        assert_eq!(app.blocks().last().map(|b| &b.hash), Some(&side_tip.hash));
+        assert_eq!(app.state.balance("main"), 0);
+        assert_eq!(app.add_block_to_chain(main), BlockStatus::Known);
+    }
+
+    #[test]
+    fn orphan_connects_once_its_parent_arrives() {
+        let mut app = App::new();
+        app.add_genesis_block();
+
+        let parent = mine_on(&app, reward("miner", 1));
+        let child = mine_after(&parent, reward("miner", 2));
+
+        assert_eq!(app.add_block_to_chain(child.clone()), BlockStatus::Orphan);
+        assert_eq!(app.tree.orphan_count(), 1);
+
+        assert_eq!(app.add_block_to_chain(parent), BlockStatus::Extended);
+        assert_eq!(app.blocks().last().map(|b| &b.hash), Some(&child.hash));
+        assert_eq!(app.tree.orphan_count(), 0);
+    }
+
+    #[test]
+    fn tampered_orphans_dont_shut_out_the_real_block() {
+        let mut app = App::new();
+        app.add_genesis_block();
+
+        let parent = mine_on(&app, reward("miner", 1));
+        let child = mine_after(&parent, reward("miner", 2));
+        let mut swapped = child.clone();
+        swapped.transactions = reward("thief", 2);
+
+        assert_eq!(app.add_block_to_chain(swapped), BlockStatus::Invalid);
+        assert_eq!(app.add_block_to_chain(child.clone()), BlockStatus::Orphan);
+        assert_eq!(app.add_block_to_chain(child.clone()), BlockStatus::Orphan, "pooled, but not part of the tree");
+        assert_eq!(app.tree.orphan_count(), 1);
+
+        assert_eq!(app.add_block_to_chain(parent), BlockStatus::Extended);
+        assert_eq!(app.blocks().last().map(|b| &b.hash), Some(&child.hash));
+    }
+
+    #[test]
+    fn utxo_coinbase_must_name_its_block_height() {
+        let mut app = App::with_mode(LedgerMode::Utxo);
+        app.add_genesis_block();
+        let reward = |height| vec![UtxoTransaction::coinbase(String::from("miner"), BLOCK_REWARD, height)];
+
+        let mut first = Block::template(app.latest_block(), app.next_difficulty_target(), vec![], reward(1));
+        first.hash = mine_block(&mut first.header);
+        assert_eq!(app.add_block_to_chain(first), BlockStatus::Extended);
+
+        // the same reward again would have the same hash as the one at height 1
+        let mut replayed = Block::template(app.latest_block(), app.next_difficulty_target(), vec![], reward(1));
+        replayed.hash = mine_block(&mut replayed.header);
+        assert_eq!(app.add_block_to_chain(replayed), BlockStatus::Invalid);
+        assert_eq!(app.utxos.balance("miner"), BLOCK_REWARD);
+    }
+
+    #[test]
+    fn orphans_without_the_minimum_work_are_rejected() {
+        let mut app = App::new();
+        app.add_genesis_block();
+
+        let mut unknown_parent = Block::genesis_block();
+        unknown_parent.hash = hex::encode([1u8; 32]);
+        // the easiest possible target, which any hash meets
+        let free = Block::new(&unknown_parent, 0x2100ffff, vec![]);
+        assert_eq!(app.add_block_to_chain(free), BlockStatus::Invalid);
+        assert_eq!(app.tree.orphan_count(), 0);
+    }
+
+    #[test]
+    fn heavier_branches_the_ledger_rejects_are_dropped() {
+        let mut app = App::new();
+        app.add_genesis_block();
+        let genesis = app.blocks()[0].clone();
+        let block = mine_on(&app, reward("local", 1));
+        assert_eq!(app.add_block_to_chain(block.clone()), BlockStatus::Extended);
+
+        // the ledger is only checked once the branch outweighs the active chain
+        let overpaid = mine_after(&genesis, vec![Transaction::coinbase(String::from("thief"), BLOCK_REWARD + 1, 1)]);
+        assert_eq!(app.add_block_to_chain(overpaid.clone()), BlockStatus::SideChain);
+        let heavier = mine_after(&overpaid, reward("thief", 2));
+        assert_eq!(app.add_block_to_chain(heavier.clone()), BlockStatus::Invalid);
+
+        assert_eq!(app.latest_block().hash, block.hash);
+        assert!(app.tree.get(&heavier.hash).is_none());
+        assert_eq!(app.balance("thief"), 0);
+        assert_eq!(app.check_chain_is_valid(&[]), Err(ChainError::Empty));
+    }
+
+    #[test]
+    fn block_with_changed_nonce_fails_validation() {
+        let mut app = App::new();
+        app.add_genesis_block();
+
+        let mut block = mine_on(&app, vec![]);
+        block.header.nonce += 1;
+        block.hash = block.header.hash();
+        assert!(!app.check_block_is_valid(app.blocks(), &block));
+    }
+
+    #[test]
+    fn timestamps_must_follow_the_median_and_not_run_ahead() {
+        let mut app = App::new();
+        app.add_genesis_block();
+        for timestamp in 1..=3 {
+            let block = mine_at(app.latest_block(), timestamp);
+            assert_eq!(app.add_block_to_chain(block), BlockStatus::Extended);
+        }
+        // timestamps 0, 1, 2, 3, so the median is 2
+        assert_eq!(median_time_past(app.blocks()), 2);
+
+        let tip = app.latest_block().clone();
+        assert!(!app.check_block_is_valid(app.blocks(), &mine_at(&tip, 2)));
+        // earlier than its parent is fine, as long as it's after the median
+        let late_parent = mine_at(&tip, 100);
+        assert_eq!(app.add_block_to_chain(late_parent.clone()), BlockStatus::Extended);
+        assert!(app.check_block_is_valid(app.blocks(), &mine_at(&late_parent, 4)));
+
+        let far_ahead = mine_at(&late_parent, Utc::now().timestamp() + MAX_FUTURE_DRIFT + 60);
+        assert!(!app.check_block_is_valid(app.blocks(), &far_ahead));
+        assert!(!app.check_block_is_valid(app.blocks(), &mine_at(&late_parent, i64::MAX)));
+    }
+
+    #[test]
+    fn block_with_wrong_difficulty_target_fails_validation() {
+        let mut app = App::new();
+        app.add_genesis_block();
+
+        let latest_block = app.latest_block();
+        let easier = pow::bits_from_target(pow::target_from_bits(DIFFICULTY_BITS) << 1);
+        let block = Block::new(latest_block, easier, vec![]);
+        assert!(!app.check_block_is_valid(app.blocks(), &block));
+    }
+
+    #[test]
+    fn header_round_trips_through_bytes() {
+        let mut header = Block::genesis_block().header;
+        header.height = 7;
+        header.nonce = 42;
+
+        let bytes = header.to_bytes();
+        assert_eq!(bytes.len(), HEADER_SIZE);
+        assert_eq!(BlockHeader::from_bytes(&bytes), Some(header));
+    }
+
+    #[test]
+    fn signed_transaction_verifies() {
+        let keys = identity::Keypair::generate_ed25519();
+        assert!(signed_transaction(&keys).verify_signature());
+    }
+
+    #[test]
+    fn tampered_transaction_is_rejected() {
+        let keys = identity::Keypair::generate_ed25519();
+        let mut tx = signed_transaction(&keys);
+        tx.amount = 1_000;
+        assert!(!tx.verify_signature());
+    }
+
+    #[test]
+    fn key_not_matching_sender_is_rejected() {
+        let keys = identity::Keypair::generate_ed25519();
+        let other = identity::Keypair::generate_ed25519();
+        let mut tx = signed_transaction(&keys);
+        tx.sender = PeerId::from(other.public()).to_string();
+        tx.sign(&keys);
+        assert!(!tx.verify_signature());
+    }
 }
\ No newline at end of file
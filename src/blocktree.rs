@@ -0,0 +1,132 @@
+use super::blockchain::Block;
+use super::pow::{self, U256};
+use std::collections::{HashMap, VecDeque};
+
+const MAX_ORPHANS: usize = 100;
+
+struct TreeEntry {
+    block: Block,
+    work: U256, // cumulative work from genesis up to and including this block
+}
+
+// Every block we know of, indexed by hash, including branches that are not the active chain.
+// Blocks whose parent hasn't arrived yet wait in a bounded orphan pool.
+#[derive(Default)]
+pub struct BlockTree {
+    entries: HashMap<String, TreeEntry>,
+    children: HashMap<String, Vec<String>>,
+    orphans: HashMap<String, Block>,
+    orphan_order: VecDeque<String>, // oldest first, for eviction
+}
+
+impl BlockTree {
+    pub fn new() -> BlockTree {
+        BlockTree::default()
+    }
+
+    // Orphans don't count: a pooled copy hasn't been validated against its parent yet.
+    pub fn contains(&self, hash: &str) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &str) -> Option<&Block> {
+        self.entries.get(hash).map(|entry| &entry.block)
+    }
+
+    pub fn work(&self, hash: &str) -> Option<U256> {
+        self.entries.get(hash).map(|entry| entry.work)
+    }
+
+    // The parent must already be in the tree, unless this is a genesis block.
+    pub fn insert(&mut self, block: Block) -> bool {
+        if self.entries.contains_key(&block.hash) {
+            return false;
+        }
+
+        let parent_work = match self.entries.get(&block.header.prev_hash) {
+            Some(parent) => parent.work,
+            None if block.header.height == 0 => U256::zero(),
+            None => return false,
+        };
+        let work = parent_work + pow::work_from_bits(block.header.difficulty_target);
+
+        self.children
+            .entry(block.header.prev_hash.clone())
+            .or_default()
+            .push(block.hash.clone());
+        self.entries.insert(block.hash.clone(), TreeEntry { block, work });
+        true
+    }
+
+    // Drops a block together with everything built on top of it.
+    pub fn remove_branch(&mut self, hash: &str) {
+        if let Some(entry) = self.entries.get(hash) {
+            if let Some(siblings) = self.children.get_mut(&entry.block.header.prev_hash) {
+                siblings.retain(|sibling| sibling != hash);
+            }
+        }
+
+        let mut pending = vec![hash.to_string()];
+        while let Some(hash) = pending.pop() {
+            self.entries.remove(&hash);
+            pending.extend(self.children.remove(&hash).unwrap_or_default());
+        }
+    }
+
+    // Blocks from genesis up to and including `hash`.
+    pub fn chain_to(&self, hash: &str) -> Vec<Block> {
+        let mut chain = vec![];
+        let mut current = self.get(hash);
+        while let Some(block) = current {
+            chain.push(block.clone());
+            current = self.get(&block.header.prev_hash);
+        }
+
+        chain.reverse();
+        chain
+    }
+
+    // Heads of every branch, the active tip included.
+    #[cfg(test)]
+    pub fn tips(&self) -> Vec<&Block> {
+        self.entries
+            .values()
+            .filter(|entry| self.children.get(&entry.block.hash).is_none_or(|c| c.is_empty()))
+            .map(|entry| &entry.block)
+            .collect()
+    }
+
+    pub fn add_orphan(&mut self, block: Block) {
+        if self.orphans.contains_key(&block.hash) {
+            return;
+        }
+        if self.orphans.len() >= MAX_ORPHANS {
+            if let Some(oldest) = self.orphan_order.pop_front() {
+                self.orphans.remove(&oldest);
+            }
+        }
+
+        self.orphan_order.push_back(block.hash.clone());
+        self.orphans.insert(block.hash.clone(), block);
+    }
+
+    pub fn take_orphans_of(&mut self, parent_hash: &str) -> Vec<Block> {
+        let hashes: Vec<String> = self
+            .orphans
+            .values()
+            .filter(|block| block.header.prev_hash == parent_hash)
+            .map(|block| block.hash.clone())
+            .collect();
+
+        self.orphan_order.retain(|hash| !hashes.contains(hash));
+        hashes
+            .iter()
+            .filter_map(|hash| self.orphans.remove(hash))
+            .collect()
+    }
+
+    #[cfg(test)]
+    pub fn orphan_count(&self) -> usize {
+        self.orphans.len()
+    }
+}
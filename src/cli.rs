@@ -0,0 +1,204 @@
+use super::blockchain::{App, Block, BlockStatus, LedgerMode, Transaction};
+use super::config::{Config, CONFIG_FILE};
+use super::keyfile;
+use super::rpc;
+use super::storage::BlockStore;
+use clap::{Parser, Subcommand};
+use libp2p::PeerId;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Parser, Debug)]
+#[clap(name = "blockchain", about = "A small proof-of-work blockchain node")]
+pub struct Cli {
+    /// TOML config file [default: blockchain.toml, if it exists]
+    #[clap(short, long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Overrides `data_dir` from the config
+    #[clap(long, global = true)]
+    pub data_dir: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>, // runs the node when left out
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run the node
+    #[clap(subcommand)]
+    Node(NodeCommand),
+    /// Create the data dir, node key and config file
+    Init,
+    /// Manage the node key
+    #[clap(subcommand)]
+    Key(KeyCommand),
+    /// Export or import the chain while the node is stopped
+    #[clap(subcommand)]
+    Chain(ChainCommand),
+    /// Sign a transfer with the node key and submit it to a running node
+    Send {
+        recipient: String,
+        amount: u64,
+        #[clap(long, default_value_t = 0)]
+        fee: u64,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum NodeCommand {
+    /// Join the network and serve the APIs
+    Run,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum KeyCommand {
+    /// Create a new node key, which changes the peer id
+    Generate {
+        /// Replace an existing key
+        #[clap(long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ChainCommand {
+    /// Write the active chain to a JSON file
+    Export { file: PathBuf },
+    /// Validate and add the blocks from a JSON file
+    Import { file: PathBuf },
+}
+
+impl Cli {
+    pub fn load_config(&self) -> Result<Config, String> {
+        let mut config = Config::load(self.config.as_deref())?;
+        if let Some(dir) = &self.data_dir {
+            config.data_dir = dir.clone();
+        }
+        Ok(config)
+    }
+}
+
+pub fn init(cli: &Cli, config: &Config) -> Result<(), String> {
+    fs::create_dir_all(&config.data_dir).map_err(|e| format!("can't create {}: {}", config.data_dir.display(), e))?;
+    let keys = keyfile::load_or_create(config.key_path()).map_err(|e| format!("can't load node key: {}", e))?;
+
+    let config_path = cli.config.clone().unwrap_or_else(|| PathBuf::from(CONFIG_FILE));
+    if config_path.exists() {
+        println!("Keeping existing config {}", config_path.display());
+    } else {
+        fs::write(&config_path, config.to_toml()).map_err(|e| format!("can't write {}: {}", config_path.display(), e))?;
+        println!("Wrote config {}", config_path.display());
+    }
+
+    println!("Data dir {}, peer id {}", config.data_dir.display(), PeerId::from(keys.public()));
+    Ok(())
+}
+
+pub fn generate_key(config: &Config, force: bool) -> Result<(), String> {
+    let path = config.key_path();
+    if force && path.exists() {
+        fs::remove_file(&path).map_err(|e| format!("can't remove {}: {}", path.display(), e))?;
+    }
+
+    let keys = keyfile::create(&path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::AlreadyExists => format!("{} already exists, pass --force to replace it", path.display()),
+        _ => format!("can't write {}: {}", path.display(), e),
+    })?;
+    println!("Wrote {}, peer id {}", path.display(), PeerId::from(keys.public()));
+    Ok(())
+}
+
+fn open_app(config: &Config) -> Result<App, String> {
+    let store = BlockStore::open(&config.data_dir).map_err(|e| format!("can't open block store: {}", e))?;
+    App::with_store(config.ledger_mode, config.difficulty, Box::new(store))
+        .map_err(|e| format!("can't load chain from block store: {}", e))
+}
+
+pub fn export_chain(config: &Config, file: &Path) -> Result<(), String> {
+    let app = open_app(config)?;
+    let json = serde_json::to_string_pretty(app.blocks()).expect("can jsonify blocks");
+    fs::write(file, json).map_err(|e| format!("can't write {}: {}", file.display(), e))?;
+    println!("Exported {} blocks to {}", app.blocks().len(), file.display());
+    Ok(())
+}
+
+pub fn import_chain(config: &Config, file: &Path) -> Result<(), String> {
+    let json = fs::read(file).map_err(|e| format!("can't read {}: {}", file.display(), e))?;
+    let blocks: Vec<Block> = serde_json::from_slice(&json).map_err(|e| format!("{} isn't a block list: {}", file.display(), e))?;
+
+    let mut app = open_app(config)?;
+    let mut statuses: HashMap<BlockStatus, usize> = HashMap::new();
+    for block in blocks {
+        *statuses.entry(app.add_block_to_chain(block)).or_default() += 1;
+    }
+
+    println!("Imported {}, height is now {}", summary(&statuses), app.latest_block().header.height);
+    if statuses.contains_key(&BlockStatus::Invalid) {
+        return Err(String::from("some blocks were invalid and skipped"));
+    }
+    Ok(())
+}
+
+fn summary(statuses: &HashMap<BlockStatus, usize>) -> String {
+    let mut counts: Vec<String> = statuses.iter().map(|(status, count)| format!("{} {:?}", count, status)).collect();
+    counts.sort();
+    counts.join(", ")
+}
+
+pub async fn send(config: &Config, recipient: String, amount: u64, fee: u64) -> Result<(), String> {
+    if config.ledger_mode != LedgerMode::Account {
+        return Err(String::from("send only supports account mode"));
+    }
+    if config.api.rpc_addr.is_empty() {
+        return Err(String::from("the JSON-RPC API is turned off in the config"));
+    }
+
+    let keys = keyfile::load(config.key_path()).map_err(|e| format!("can't load node key: {}", e))?;
+    let sender = PeerId::from(keys.public()).to_string();
+    let account = rpc::request(&config.api.rpc_addr, "getBalance", json!([sender])).await?;
+    let nonce = account["nonce"].as_u64().ok_or("node didn't report a nonce")?;
+
+    let mut tx = Transaction::new(sender, recipient, amount, fee, nonce);
+    tx.sign(&keys);
+    let hash = rpc::request(&config.api.rpc_addr, "submitTransaction", json!([tx])).await?;
+    println!("Submitted transaction {}", hash.as_str().unwrap_or_default());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subcommands_parse() {
+        let cli = Cli::parse_from(["blockchain", "--config", "node.toml", "send", "bob", "5", "--fee", "1"]);
+        assert_eq!(cli.config, Some(PathBuf::from("node.toml")));
+        assert!(matches!(cli.command, Some(Command::Send { amount: 5, fee: 1, .. })));
+
+        let cli = Cli::parse_from(["blockchain", "chain", "export", "chain.json", "--data-dir", "other"]);
+        assert!(matches!(cli.command, Some(Command::Chain(ChainCommand::Export { .. }))));
+        assert_eq!(cli.load_config().expect("defaults").data_dir, PathBuf::from("other"));
+
+        assert!(Cli::parse_from(["blockchain"]).command.is_none());
+        assert!(Cli::try_parse_from(["blockchain", "key", "rotate"]).is_err());
+    }
+
+    #[test]
+    fn init_sets_up_an_empty_dir() {
+        let dir = std::env::temp_dir().join(format!("blockchain-init-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let config_path = dir.join(CONFIG_FILE);
+        let cli = Cli::parse_from(["blockchain", "--config", config_path.to_str().expect("utf-8 temp dir"), "init"]);
+        let config = Config { data_dir: dir.join("data"), ..Config::default() };
+
+        init(&cli, &config).expect("init works on a fresh dir");
+        let key = keyfile::load(config.key_path()).expect("init created the key");
+        assert!(config_path.exists());
+
+        init(&cli, &config).expect("init can run again");
+        assert_eq!(PeerId::from(keyfile::load(config.key_path()).expect("key kept").public()), PeerId::from(key.public()));
+    }
+}
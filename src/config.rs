@@ -0,0 +1,199 @@
+use super::blockchain::LedgerMode;
+use super::keyfile;
+use super::pow::DifficultyConfig;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const CONFIG_FILE: &str = "blockchain.toml";
+
+// Node settings, read from a TOML file. Every field is optional there; the environment
+// variables older setups use (DATA_DIR, BOOTSTRAP_PEERS, ...) still override the file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub data_dir: PathBuf,
+    pub key_file: Option<PathBuf>, // defaults to node.key in `data_dir`
+    pub ledger_mode: LedgerMode,
+    pub log_level: String, // RUST_LOG syntax, used unless RUST_LOG is set
+    pub init_delay_secs: u64, // wait before asking peers for their tips, so mDNS can find some
+    pub network: NetworkConfig,
+    pub api: ApiConfig,
+    pub mining: MiningConfig,
+    pub difficulty: DifficultyConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    pub listen_addrs: Vec<String>,
+    pub bootstrap_peers: Vec<String>, // multiaddrs ending in /p2p/<peer id>
+}
+
+// An empty address turns that API off.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiConfig {
+    pub rpc_addr: String,
+    pub rest_addr: String,
+    pub ws_addr: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MiningConfig {
+    pub enabled: bool,
+    pub interval_secs: u64, // time between attempts, mining itself takes extra
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            data_dir: PathBuf::from("data"),
+            key_file: None,
+            ledger_mode: LedgerMode::Account,
+            log_level: String::from("info"),
+            init_delay_secs: 1,
+            network: NetworkConfig::default(),
+            api: ApiConfig::default(),
+            mining: MiningConfig::default(),
+            difficulty: DifficultyConfig::default(),
+        }
+    }
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            listen_addrs: vec![String::from("/ip4/0.0.0.0/tcp/0")],
+            bootstrap_peers: vec![],
+        }
+    }
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        ApiConfig {
+            rpc_addr: String::from("127.0.0.1:8545"),
+            rest_addr: String::from("127.0.0.1:8080"),
+            ws_addr: String::from("127.0.0.1:8546"),
+        }
+    }
+}
+
+impl Default for MiningConfig {
+    fn default() -> Self {
+        MiningConfig {
+            enabled: false,
+            interval_secs: 10,
+        }
+    }
+}
+
+impl Config {
+    // A missing file is fine when it's the default one; any other has to exist.
+    pub fn load(path: Option<&Path>) -> Result<Config, String> {
+        let (path, required) = match path {
+            Some(path) => (path, true),
+            None => (Path::new(CONFIG_FILE), false),
+        };
+
+        let mut config = match fs::read_to_string(path) {
+            Ok(text) => Config::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))?,
+            Err(e) if !required && e.kind() == std::io::ErrorKind::NotFound => Config::default(),
+            Err(e) => return Err(format!("can't read {}: {}", path.display(), e)),
+        };
+        config.apply_env();
+        Ok(config)
+    }
+
+    pub fn parse(text: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).expect("can serialize config")
+    }
+
+    pub fn key_path(&self) -> PathBuf {
+        self.key_file.clone().unwrap_or_else(|| self.data_dir.join(keyfile::KEY_FILE))
+    }
+
+    fn apply_env(&mut self) {
+        let var = |name| std::env::var(name).ok().filter(|value: &String| !value.is_empty());
+        let list = |value: String| value.split(',').map(|item| item.trim().to_string()).filter(|item| !item.is_empty()).collect();
+
+        if let Some(dir) = var("DATA_DIR") {
+            self.data_dir = PathBuf::from(dir);
+        }
+        if let Some(file) = var("KEY_FILE") {
+            self.key_file = Some(PathBuf::from(file));
+        }
+        match var("LEDGER_MODE").as_deref() {
+            Some("utxo") => self.ledger_mode = LedgerMode::Utxo,
+            Some("account") => self.ledger_mode = LedgerMode::Account,
+            _ => {}
+        }
+        if let Some(addrs) = var("LISTEN_ADDR") {
+            self.network.listen_addrs = list(addrs);
+        }
+        if let Some(peers) = var("BOOTSTRAP_PEERS") {
+            self.network.bootstrap_peers = list(peers);
+        }
+        if let Some(addr) = var("RPC_ADDR") {
+            self.api.rpc_addr = addr;
+        }
+        if let Some(addr) = var("REST_ADDR") {
+            self.api.rest_addr = addr;
+        }
+        if let Some(addr) = var("WS_ADDR") {
+            self.api.ws_addr = addr;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_fields_take_defaults() {
+        let config = Config::parse(
+            r#"
+            ledger_mode = "utxo"
+
+            [network]
+            bootstrap_peers = ["/ip4/10.0.0.2/tcp/4001/p2p/12D3KooWRBhwfeP2Y4TCx1SM6s9rUoHhR5STiGwxBhgFRcw3UERE"]
+
+            [mining]
+            enabled = true
+
+            [difficulty]
+            target_block_time = 30
+            "#,
+        )
+        .expect("valid config");
+
+        assert_eq!(config.ledger_mode, LedgerMode::Utxo);
+        assert_eq!(config.network.listen_addrs, NetworkConfig::default().listen_addrs);
+        assert_eq!(config.network.bootstrap_peers.len(), 1);
+        assert!(config.mining.enabled);
+        assert_eq!(config.mining.interval_secs, 10);
+        assert_eq!(config.difficulty.target_block_time, 30);
+        assert_eq!(config.difficulty.retarget_interval, DifficultyConfig::default().retarget_interval);
+        assert_eq!(config.key_path(), Path::new("data").join(keyfile::KEY_FILE));
+
+        assert!(Config::parse("listen = true").is_err());
+    }
+
+    #[test]
+    fn written_config_reads_back() {
+        let config = Config {
+            key_file: Some(PathBuf::from("/etc/blockchain/node.key")),
+            api: ApiConfig { rest_addr: String::new(), ..ApiConfig::default() },
+            ..Config::default()
+        };
+        assert_eq!(Config::parse(&config.to_toml()), Ok(config));
+        assert_eq!(Config::parse(&Config::default().to_toml()), Ok(Config::default()));
+    }
+}
@@ -0,0 +1,501 @@
+use super::blockchain::{App, Block, BlockHeader, BlockStatus};
+use super::pow;
+use super::sync::SyncCodec;
+use super::wire::SyncRequest;
+use libp2p::request_response::{RequestId, RequestResponse};
+use libp2p::PeerId;
+use log::{error, info};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+pub const HEADERS_PER_REQUEST: usize = 500;
+pub const BLOCKS_PER_REQUEST: usize = 16;
+const MAX_BATCHES_IN_FLIGHT: usize = 8; // requested or buffered, not yet connected
+
+// Sends the downloader's requests. The id comes back with the response or the failure.
+pub trait Requests {
+    fn send(&mut self, peer: &PeerId, request: SyncRequest) -> RequestId;
+}
+
+impl Requests for RequestResponse<SyncCodec> {
+    fn send(&mut self, peer: &PeerId, request: SyncRequest) -> RequestId {
+        self.send_request(peer, request)
+    }
+}
+
+// Headers-first sync. Headers are downloaded from the best peer and checked for linkage and
+// proof of work, then their bodies are fetched in batches from every peer that has them, one
+// batch per peer at a time. Bodies are connected in height order as they arrive.
+pub struct Downloader {
+    batch_size: usize, // bodies per request
+    tips: HashMap<PeerId, u32>, // reported tip heights
+    withheld: HashSet<PeerId>, // answered a batch short, so never asked for this sync's bodies again
+    headers_request: Option<(RequestId, PeerId)>, // the header download in progress
+    last_header: Option<BlockHeader>, // the next header batch has to link to this one
+    queued: VecDeque<BlockHeader>, // bodies not requested yet, in height order
+    in_flight: HashMap<RequestId, (PeerId, Vec<BlockHeader>)>,
+    arrived: HashMap<String, Block>, // bodies waiting for their parent to be connected
+    expected: VecDeque<String>, // every body not connected yet, in height order
+}
+
+impl Downloader {
+    pub fn new() -> Downloader {
+        Downloader::with_batch_size(BLOCKS_PER_REQUEST)
+    }
+
+    pub fn with_batch_size(batch_size: usize) -> Downloader {
+        Downloader {
+            batch_size: batch_size.max(1),
+            tips: HashMap::new(),
+            withheld: HashSet::new(),
+            headers_request: None,
+            last_header: None,
+            queued: VecDeque::new(),
+            in_flight: HashMap::new(),
+            arrived: HashMap::new(),
+            expected: VecDeque::new(),
+        }
+    }
+
+    pub fn is_syncing(&self) -> bool {
+        self.headers_request.is_some() || !self.expected.is_empty()
+    }
+
+    pub fn on_tip(&mut self, requests: &mut impl Requests, peer: PeerId, height: u32, hash: &str, app: &App) {
+        self.tips.insert(peer, height);
+        let behind = height > app.latest_block().header.height && !app.tree.contains(hash);
+        if behind && !self.is_syncing() {
+            info!("{} is at height {}, downloading headers", peer, height);
+            self.last_header = None;
+            self.withheld.clear(); // a new sync, so possibly new headers
+            let id = requests.send(&peer, SyncRequest::Headers { locator: app.locator() });
+            self.headers_request = Some((id, peer));
+            return;
+        }
+
+        self.schedule(requests);
+    }
+
+    pub fn on_headers(&mut self, requests: &mut impl Requests, id: RequestId, headers: Vec<BlockHeader>, app: &App) {
+        let peer = match self.headers_request {
+            Some((expected, peer)) if expected == id => peer,
+            _ => return,
+        };
+
+        let full = headers.len() >= HEADERS_PER_REQUEST;
+        for header in headers {
+            if !self.links(&header, app) {
+                error!("{} sent a header that doesn't extend a known chain, dropping it", peer);
+                self.on_failure(requests, id, peer);
+                return;
+            }
+
+            let hash = header.hash();
+            if !app.tree.contains(&hash) {
+                self.expected.push_back(hash);
+                self.queued.push_back(header.clone());
+            }
+            self.last_header = Some(header);
+        }
+
+        match (&self.last_header, full) {
+            (Some(last), true) => {
+                let id = requests.send(&peer, SyncRequest::Headers { locator: vec![last.hash()] });
+                self.headers_request = Some((id, peer));
+            }
+            _ => {
+                info!("Header download from {} finished, {} bodies to fetch", peer, self.queued.len());
+                self.headers_request = None;
+            }
+        }
+
+        self.schedule(requests);
+    }
+
+    pub fn on_blocks(&mut self, requests: &mut impl Requests, id: RequestId, blocks: Vec<Block>, app: &mut App) {
+        // a response to a batch we gave up on, or to a request that wasn't ours
+        let (peer, requested) = match self.in_flight.remove(&id) {
+            Some(batch) => batch,
+            None => return,
+        };
+        let mut blocks: HashMap<String, Block> = blocks.into_iter().map(|block| (block.hash.clone(), block)).collect();
+
+        let mut missing = vec![];
+        for header in requested {
+            match blocks.remove(&header.hash()) {
+                Some(block) => {
+                    self.arrived.insert(block.hash.clone(), block);
+                }
+                None => missing.push(header),
+            }
+        }
+        let short = !missing.is_empty();
+        for header in missing.into_iter().rev() {
+            self.queued.push_front(header);
+        }
+
+        while let Some(block) = self.expected.front().and_then(|hash| self.arrived.remove(hash)) {
+            self.expected.pop_front();
+            if app.add_block_to_chain(block) == BlockStatus::Invalid {
+                error!("Downloaded block from {} is invalid, abandoning sync", peer);
+                self.reset();
+                return;
+            }
+        }
+
+        // It may be on another fork or holding bodies back; asking again would loop forever.
+        if short {
+            info!("{} didn't send every block it was asked for, dropping it", peer);
+            self.tips.remove(&peer);
+            self.withheld.insert(peer);
+            if !self.has_peers() {
+                self.abandon();
+                return;
+            }
+        }
+
+        self.schedule(requests);
+    }
+
+    // A request failed, or the peer sent something unusable. A body batch it owed us goes to
+    // other peers; once no peer is left the sync is abandoned rather than left waiting.
+    pub fn on_failure(&mut self, requests: &mut impl Requests, id: RequestId, peer: PeerId) {
+        self.tips.remove(&peer);
+        if let Some((_, headers)) = self.in_flight.remove(&id) {
+            for header in headers.into_iter().rev() {
+                self.queued.push_front(header);
+            }
+        }
+        if matches!(self.headers_request, Some((expected, _)) if expected == id) {
+            self.headers_request = None;
+        }
+
+        if !self.has_peers() {
+            self.abandon();
+            return;
+        }
+
+        self.schedule(requests);
+    }
+
+    fn has_peers(&self) -> bool {
+        self.tips.keys().any(|peer| !self.withheld.contains(peer))
+    }
+
+    fn abandon(&mut self) {
+        if self.is_syncing() {
+            info!("No peers left to sync from, abandoning sync");
+        }
+        self.reset();
+    }
+
+    // Cheap checks before any body is fetched: the header extends its parent, the target only
+    // moves on retarget boundaries and never past the limit, and the proof of work meets it.
+    // The exact retarget is checked when the body connects.
+    fn links(&self, header: &BlockHeader, app: &App) -> bool {
+        let parent = match &self.last_header {
+            Some(last) => Some((last.hash(), last.height, last.difficulty_target)),
+            None => app
+                .tree
+                .get(&header.prev_hash)
+                .map(|block| (block.hash.clone(), block.header.height, block.header.difficulty_target)),
+        };
+
+        match parent {
+            Some((hash, height, bits)) => {
+                header.prev_hash == hash
+                    && header.height == height + 1
+                    && (header.difficulty_target == bits || pow::is_retarget_height(header.height, &app.difficulty))
+                    && pow::within_limit(header.difficulty_target)
+                    && pow::meets_target(&hex::decode(header.hash()).unwrap_or_default(), header.difficulty_target)
+            }
+            None => false,
+        }
+    }
+
+    // Hands the next batches to idle peers whose tip covers them.
+    fn schedule(&mut self, requests: &mut impl Requests) {
+        let mut peers: Vec<(PeerId, u32)> = self
+            .tips
+            .iter()
+            .filter(|(peer, _)| !self.withheld.contains(peer))
+            .map(|(peer, height)| (*peer, *height))
+            .collect();
+        peers.sort_by_key(|(_, height)| std::cmp::Reverse(*height));
+
+        for (peer, height) in peers {
+            let waiting = self.expected.len() - self.queued.len();
+            let busy = self.in_flight.values().any(|(other, _)| *other == peer);
+            if busy || waiting >= MAX_BATCHES_IN_FLIGHT * self.batch_size {
+                continue;
+            }
+
+            let mut batch = vec![];
+            while batch.len() < self.batch_size {
+                match self.queued.front() {
+                    Some(header) if header.height <= height => batch.push(self.queued.pop_front().expect("front exists")),
+                    _ => break,
+                }
+            }
+            if batch.is_empty() {
+                continue;
+            }
+
+            let hashes = batch.iter().map(|header| header.hash()).collect();
+            let id = requests.send(&peer, SyncRequest::Blocks(hashes));
+            self.in_flight.insert(id, (peer, batch));
+        }
+    }
+
+    fn reset(&mut self) {
+        self.withheld.clear();
+        self.headers_request = None;
+        self.last_header = None;
+        self.queued.clear();
+        self.in_flight.clear();
+        self.arrived.clear();
+        self.expected.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::mine_block;
+    use crate::blockchain::tests::app_with_blocks;
+    use crate::sync;
+    use libp2p::identity;
+
+    fn peer() -> PeerId {
+        PeerId::from(identity::Keypair::generate_ed25519().public())
+    }
+
+    // Gets real ids from an unconnected behaviour and keeps every request for the test to answer.
+    struct Recorder {
+        sync: RequestResponse<SyncCodec>,
+        sent: Vec<(RequestId, PeerId, SyncRequest)>,
+    }
+
+    impl Recorder {
+        fn new() -> Recorder {
+            Recorder { sync: sync::new_behaviour(), sent: vec![] }
+        }
+
+        fn take(&mut self) -> Vec<(RequestId, PeerId, SyncRequest)> {
+            std::mem::take(&mut self.sent)
+        }
+    }
+
+    impl Requests for Recorder {
+        fn send(&mut self, peer: &PeerId, request: SyncRequest) -> RequestId {
+            let id = self.sync.send_request(peer, request.clone());
+            self.sent.push((id, *peer, request));
+            id
+        }
+    }
+
+    fn blocks_for(source: &App, hashes: &[String]) -> Vec<Block> {
+        hashes.iter().filter_map(|hash| source.tree.get(hash).cloned()).collect()
+    }
+
+    #[test]
+    fn locator_finds_the_fork_point() {
+        let source = app_with_blocks(3);
+        let locator = source.locator();
+        assert_eq!(locator.len(), 4);
+        assert_eq!(locator.last(), Some(&source.blocks()[0].hash));
+
+        let headers = source.headers_after(&[source.blocks()[1].hash.clone()], HEADERS_PER_REQUEST);
+        assert_eq!(headers.iter().map(|h| h.height).collect::<Vec<_>>(), vec![2, 3]);
+    }
+
+    #[test]
+    fn syncs_headers_then_bodies_from_several_peers() {
+        let source = app_with_blocks(3);
+        let tip = source.latest_block().clone();
+        let mut app = App::new();
+        app.add_genesis_block();
+        let mut downloader = Downloader::with_batch_size(2);
+        let mut requests = Recorder::new();
+        let (first, second) = (peer(), peer());
+
+        downloader.on_tip(&mut requests, first, tip.header.height, &tip.hash, &app);
+        let (id, locator) = match &requests.take()[..] {
+            [(id, _, SyncRequest::Headers { locator })] => (*id, locator.clone()),
+            sent => panic!("expected a header request, got {:?}", sent),
+        };
+        downloader.on_tip(&mut requests, second, tip.header.height, &tip.hash, &app);
+        assert!(requests.sent.is_empty());
+
+        let headers = source.headers_after(&locator, HEADERS_PER_REQUEST);
+        downloader.on_headers(&mut requests, id, headers, &app);
+        let sent = requests.take();
+        assert_eq!(sent.len(), 2, "both peers get a batch");
+
+        // the second batch arrives first and waits for the first one
+        for (id, _, request) in sent.into_iter().rev() {
+            if let SyncRequest::Blocks(hashes) = request {
+                downloader.on_blocks(&mut requests, id, blocks_for(&source, &hashes), &mut app);
+            }
+        }
+
+        assert_eq!(app.latest_block().hash, tip.hash);
+        assert!(!downloader.is_syncing());
+    }
+
+    #[test]
+    fn responses_are_matched_by_request_not_by_peer() {
+        let source = app_with_blocks(3);
+        let tip = source.latest_block().clone();
+        let mut app = app_with_blocks(0);
+        let mut downloader = Downloader::with_batch_size(2);
+        let mut requests = Recorder::new();
+        let (first, second) = (peer(), peer());
+
+        downloader.on_tip(&mut requests, first, tip.header.height, &tip.hash, &app);
+        downloader.on_tip(&mut requests, second, tip.header.height, &tip.hash, &app);
+        let (headers_id, _, _) = requests.take()[0].clone();
+        downloader.on_headers(&mut requests, headers_id, source.headers_after(&app.locator(), HEADERS_PER_REQUEST), &app);
+        let sent = requests.take();
+        let (stale, stale_peer, _) = sent.iter().find(|(_, peer, _)| *peer == first).cloned().expect("first has a batch");
+
+        // the first batch times out and is handed to the second peer once it's idle
+        downloader.on_failure(&mut requests, stale, stale_peer);
+        for (id, _, request) in sent.into_iter().filter(|(id, _, _)| *id != stale) {
+            if let SyncRequest::Blocks(hashes) = request {
+                downloader.on_blocks(&mut requests, id, blocks_for(&source, &hashes), &mut app);
+            }
+        }
+        let retry = requests.take();
+        assert!(matches!(&retry[..], [(_, peer, SyncRequest::Blocks(_))] if *peer == second));
+
+        // a late answer to the failed request changes nothing
+        let height = app.latest_block().header.height;
+        let stale_blocks = match &retry[0] {
+            (_, _, SyncRequest::Blocks(hashes)) => blocks_for(&source, hashes),
+            _ => unreachable!(),
+        };
+        downloader.on_blocks(&mut requests, stale, stale_blocks, &mut app);
+        assert_eq!(app.latest_block().header.height, height);
+        assert!(downloader.is_syncing());
+
+        for (id, _, request) in retry {
+            if let SyncRequest::Blocks(hashes) = request {
+                downloader.on_blocks(&mut requests, id, blocks_for(&source, &hashes), &mut app);
+            }
+        }
+        assert_eq!(app.latest_block().hash, tip.hash);
+    }
+
+    #[test]
+    fn failed_tip_requests_keep_body_batches_in_flight() {
+        let source = app_with_blocks(2);
+        let tip = source.latest_block().clone();
+        let mut app = app_with_blocks(0);
+        let mut downloader = Downloader::with_batch_size(2);
+        let mut requests = Recorder::new();
+        let (syncing, other) = (peer(), peer());
+
+        downloader.on_tip(&mut requests, syncing, tip.header.height, &tip.hash, &app);
+        downloader.on_tip(&mut requests, other, 0, &app.latest_block().hash, &app);
+        let (headers_id, _, _) = requests.take()[0].clone();
+        downloader.on_headers(&mut requests, headers_id, source.headers_after(&app.locator(), HEADERS_PER_REQUEST), &app);
+        let (batch, _, _) = requests.take()[0].clone();
+
+        // a tip request to the same peer fails, but the batch is still owed and isn't sent again
+        let tip_request = requests.send(&syncing, SyncRequest::Tip);
+        downloader.on_failure(&mut requests, tip_request, syncing);
+        assert_eq!(requests.take().len(), 1);
+        assert!(downloader.is_syncing());
+
+        downloader.on_blocks(&mut requests, batch, source.blocks()[1..].to_vec(), &mut app);
+        assert_eq!(app.latest_block().hash, tip.hash);
+    }
+
+    #[test]
+    fn sync_is_abandoned_once_no_peers_are_left() {
+        let source = app_with_blocks(2);
+        let tip = source.latest_block().clone();
+        let app = app_with_blocks(0);
+        let mut downloader = Downloader::new();
+        let mut requests = Recorder::new();
+        let peer = peer();
+
+        downloader.on_tip(&mut requests, peer, tip.header.height, &tip.hash, &app);
+        let (headers_id, _, _) = requests.take()[0].clone();
+        downloader.on_headers(&mut requests, headers_id, source.headers_after(&app.locator(), HEADERS_PER_REQUEST), &app);
+        let (batch, _, _) = requests.take()[0].clone();
+        assert!(downloader.is_syncing());
+
+        downloader.on_failure(&mut requests, batch, peer);
+        assert!(!downloader.is_syncing());
+        assert!(requests.sent.is_empty());
+    }
+
+    #[test]
+    fn peers_that_withhold_bodies_are_dropped() {
+        let source = app_with_blocks(2);
+        let tip = source.latest_block().clone();
+        let mut app = app_with_blocks(0);
+        let mut downloader = Downloader::new();
+        let mut requests = Recorder::new();
+        let (withholding, honest) = (peer(), peer());
+
+        downloader.on_tip(&mut requests, withholding, tip.header.height, &tip.hash, &app);
+        let (headers_id, _, _) = requests.take()[0].clone();
+        downloader.on_headers(&mut requests, headers_id, source.headers_after(&app.locator(), HEADERS_PER_REQUEST), &app);
+        let (batch, _, _) = requests.take()[0].clone();
+        downloader.on_tip(&mut requests, honest, tip.header.height, &tip.hash, &app);
+        assert!(requests.sent.is_empty(), "the only batch is still owed");
+
+        // the batch goes to the other peer, and a new tip doesn't bring the first one back
+        downloader.on_blocks(&mut requests, batch, vec![], &mut app);
+        downloader.on_tip(&mut requests, withholding, tip.header.height, &tip.hash, &app);
+        let retry = requests.take();
+        assert!(matches!(&retry[..], [(_, peer, SyncRequest::Blocks(_))] if *peer == honest));
+
+        // once nobody can serve the bodies the sync is abandoned
+        downloader.on_blocks(&mut requests, retry[0].0, vec![], &mut app);
+        assert!(!downloader.is_syncing());
+        assert!(requests.sent.is_empty());
+        assert_eq!(app.latest_block().header.height, 0);
+    }
+
+    #[test]
+    fn headers_that_dont_link_are_dropped() {
+        let source = app_with_blocks(2);
+        let tip = source.latest_block().clone();
+        let app = app_with_blocks(0);
+        let mut downloader = Downloader::new();
+        let mut requests = Recorder::new();
+        let peer = peer();
+
+        downloader.on_tip(&mut requests, peer, tip.header.height, &tip.hash, &app);
+        let (id, _, _) = requests.take()[0].clone();
+        let mut headers = source.headers_after(&app.locator(), HEADERS_PER_REQUEST);
+        headers.remove(0);
+
+        downloader.on_headers(&mut requests, id, headers, &app);
+        assert!(requests.sent.is_empty());
+        assert!(!downloader.is_syncing());
+    }
+
+    #[test]
+    fn headers_with_an_easier_target_are_dropped() {
+        let source = app_with_blocks(1);
+        let tip = source.latest_block().clone();
+        let app = app_with_blocks(0);
+        let mut downloader = Downloader::new();
+        let mut requests = Recorder::new();
+        let peer = peer();
+
+        downloader.on_tip(&mut requests, peer, tip.header.height, &tip.hash, &app);
+        let (id, _, _) = requests.take()[0].clone();
+        let mut header = tip.header.clone();
+        header.difficulty_target = 0x2100ffff; // any hash meets it
+        mine_block(&mut header);
+
+        downloader.on_headers(&mut requests, id, vec![header], &app);
+        assert!(requests.sent.is_empty());
+        assert!(!downloader.is_syncing());
+    }
+}
@@ -0,0 +1,73 @@
+use super::blockchain::Block;
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum ChainEvent {
+    // A block became the tip, either extending the chain or as part of a reorg.
+    NewHead {
+        height: u32,
+        hash: String,
+        prev_hash: String,
+        timestamp: i64,
+    },
+    // A transaction was admitted to the mempool.
+    PendingTransaction {
+        hash: String,
+    },
+    // The tip moved to another branch: `disconnected` blocks were rolled back, `connected` applied.
+    Reorg {
+        fork_height: u32,
+        old_tip: String,
+        new_tip: String,
+        disconnected: Vec<String>,
+        connected: Vec<String>,
+    },
+    // First connection to a peer; not about the chain, but subscribers get it from the same feed.
+    PeerConnected {
+        peer: String,
+    },
+}
+
+impl ChainEvent {
+    pub fn new_head(block: &Block) -> ChainEvent {
+        ChainEvent::NewHead {
+            height: block.header.height,
+            hash: block.hash.clone(),
+            prev_hash: block.header.prev_hash.clone(),
+            timestamp: block.header.timestamp,
+        }
+    }
+
+    // Name subscribers use to ask for this kind of event.
+    pub fn topic(&self) -> &'static str {
+        match self {
+            ChainEvent::NewHead { .. } => "newHeads",
+            ChainEvent::PendingTransaction { .. } => "newPendingTransactions",
+            ChainEvent::Reorg { .. } => "reorg",
+            ChainEvent::PeerConnected { .. } => "peerConnected",
+        }
+    }
+}
+
+// Every name `ChainEvent::topic` returns.
+pub const TOPICS: [&str; 4] = ["newHeads", "newPendingTransactions", "reorg", "peerConnected"];
+
+// Fans chain events out to every subscriber, forgetting the ones that hung up.
+#[derive(Default)]
+pub struct EventBus {
+    subscribers: Vec<mpsc::UnboundedSender<ChainEvent>>,
+}
+
+impl EventBus {
+    pub fn subscribe(&mut self) -> mpsc::UnboundedReceiver<ChainEvent> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    pub fn emit(&mut self, event: ChainEvent) {
+        self.subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
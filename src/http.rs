@@ -0,0 +1,299 @@
+use log::error;
+use serde::Serialize;
+use serde_json::json;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::spawn;
+use tokio::sync::{mpsc, oneshot};
+
+const MAX_HEADERS: usize = 32;
+const MAX_REQUEST: usize = 1024 * 1024;
+
+// Just enough HTTP/1.1 for the local APIs: one request per connection, bodies sized by
+// Content-Length, and every response closes the connection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub path: String, // query string included
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn json<T: Serialize>(status: u16, body: &T) -> Response {
+        Response {
+            status,
+            content_type: "application/json",
+            body: serde_json::to_vec(body).expect("can jsonify response"),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            reason(self.status),
+            self.content_type,
+            self.body.len()
+        )
+        .into_bytes();
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+fn invalid_data(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+// Why a request couldn't be read. Malformed and oversized requests still get an answer.
+#[derive(Debug)]
+pub enum RequestError {
+    Io(io::Error),
+    Malformed(String),
+    TooLarge,
+}
+
+impl RequestError {
+    pub fn status(&self) -> u16 {
+        match self {
+            RequestError::Io(_) => 500,
+            RequestError::Malformed(_) => 400,
+            RequestError::TooLarge => 413,
+        }
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RequestError::Io(e) => write!(f, "{}", e),
+            RequestError::Malformed(reason) => write!(f, "malformed request: {}", reason),
+            RequestError::TooLarge => write!(f, "request is larger than {} bytes", MAX_REQUEST),
+        }
+    }
+}
+
+impl From<io::Error> for RequestError {
+    fn from(e: io::Error) -> Self {
+        RequestError::Io(e)
+    }
+}
+
+// Parses a request from the start of `buf`, or returns `None` if more bytes are needed.
+pub fn parse_request(buf: &[u8]) -> Result<Option<Request>, RequestError> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut parsed = httparse::Request::new(&mut headers);
+    let body_start = match parsed.parse(buf).map_err(|e| RequestError::Malformed(e.to_string()))? {
+        httparse::Status::Complete(len) => len,
+        httparse::Status::Partial => return Ok(None),
+    };
+
+    let headers: Vec<(String, String)> = parsed
+        .headers
+        .iter()
+        .map(|header| (header.name.to_string(), String::from_utf8_lossy(header.value).into_owned()))
+        .collect();
+    let mut request = Request {
+        method: parsed.method.unwrap_or_default().to_string(),
+        path: parsed.path.unwrap_or_default().to_string(),
+        headers,
+        body: vec![],
+    };
+
+    let content_length = match request.header("Content-Length") {
+        Some(len) => {
+            len.trim().parse::<usize>().map_err(|e| RequestError::Malformed(format!("bad Content-Length: {}", e)))?
+        }
+        None => 0,
+    };
+    let body_end = body_start
+        .checked_add(content_length)
+        .filter(|end| *end <= MAX_REQUEST)
+        .ok_or(RequestError::TooLarge)?;
+    if buf.len() < body_end {
+        return Ok(None);
+    }
+
+    request.body = buf[body_start..body_end].to_vec();
+    Ok(Some(request))
+}
+
+// Reads one request, or `None` if the client hung up before sending a complete one.
+pub async fn read_request(stream: &mut TcpStream) -> Result<Option<Request>, RequestError> {
+    let mut buf = vec![];
+    let mut chunk = [0u8; 4096];
+    loop {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..read]);
+
+        if let Some(request) = parse_request(&buf)? {
+            return Ok(Some(request));
+        }
+        if buf.len() > MAX_REQUEST {
+            return Err(RequestError::TooLarge);
+        }
+    }
+}
+
+pub async fn write_response(stream: &mut TcpStream, response: &Response) -> io::Result<()> {
+    stream.write_all(&response.to_bytes()).await?;
+    stream.shutdown().await
+}
+
+// Splits a complete response into its status and body.
+pub fn parse_response(buf: &[u8]) -> io::Result<(u16, Vec<u8>)> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut parsed = httparse::Response::new(&mut headers);
+    match parsed.parse(buf).map_err(|e| invalid_data(e.to_string()))? {
+        httparse::Status::Complete(len) => Ok((parsed.code.unwrap_or_default(), buf[len..].to_vec())),
+        httparse::Status::Partial => Err(invalid_data(String::from("response ended early"))),
+    }
+}
+
+// POSTs `body` as JSON to `addr` and returns the response status and body.
+pub async fn post_json<T: Serialize>(addr: &str, path: &str, body: &T) -> io::Result<(u16, Vec<u8>)> {
+    let body = serde_json::to_vec(body).expect("can jsonify request");
+    let head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        path,
+        addr,
+        body.len()
+    );
+
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&body).await?;
+    let mut response = vec![];
+    stream.read_to_end(&mut response).await?;
+    parse_response(&response)
+}
+
+// Answers every connection on `listener` with `handler`, each on its own task.
+pub async fn serve<F, Fut>(listener: TcpListener, handler: F)
+where
+    F: Fn(Request) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = Response> + Send,
+{
+    loop {
+        match listener.accept().await {
+            Ok((mut stream, _)) => {
+                let handler = handler.clone();
+                spawn(async move {
+                    let result = match read_request(&mut stream).await {
+                        Ok(Some(request)) => write_response(&mut stream, &handler(request).await).await,
+                        Ok(None) => Ok(()),
+                        Err(RequestError::Io(e)) => Err(e),
+                        Err(e) => {
+                            let response = Response::json(e.status(), &json!({ "error": e.to_string() }));
+                            write_response(&mut stream, &response).await
+                        }
+                    };
+                    if let Err(e) = result {
+                        error!("HTTP connection failed: {}", e);
+                    }
+                });
+            }
+            Err(e) => error!("can't accept HTTP connection: {}", e),
+        }
+    }
+}
+
+// A request on its way from an API server to the swarm loop, which owns the chain and
+// answers on `reply`.
+pub struct Call<Req, Res> {
+    pub request: Req,
+    pub reply: oneshot::Sender<Res>,
+}
+
+impl<Req, Res> Call<Req, Res> {
+    // Hands `request` to the swarm loop and waits for the answer. The error says why none came.
+    pub async fn send(calls: &mpsc::UnboundedSender<Call<Req, Res>>, request: Req) -> Result<Res, &'static str> {
+        let (reply, answer) = oneshot::channel();
+        if calls.send(Call { request, reply }).is_err() {
+            return Err("node is shutting down");
+        }
+        answer.await.map_err(|_| "node dropped the request")
+    }
+
+    pub fn answer(self, response: Res) {
+        // the client may have hung up already
+        let _ = self.reply.send(response);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_are_parsed_once_the_body_arrived() {
+        let raw = b"POST /rpc HTTP/1.1\r\nHost: localhost\r\ncontent-length: 12\r\n\r\n{\"id\": 1234}";
+        assert_eq!(parse_request(&raw[..raw.len() - 1]).expect("valid so far"), None);
+
+        let request = parse_request(raw).expect("valid request").expect("complete request");
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/rpc");
+        assert_eq!(request.header("Content-Length"), Some("12"));
+        assert_eq!(request.body, b"{\"id\": 1234}".to_vec());
+
+        assert!(matches!(parse_request(b"NOT HTTP\r\n\r\n"), Err(RequestError::Malformed(_))));
+    }
+
+    #[test]
+    fn oversized_bodies_are_refused_before_they_arrive() {
+        let too_long = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_REQUEST);
+        assert!(matches!(parse_request(too_long.as_bytes()), Err(RequestError::TooLarge)));
+
+        // would wrap around when added to the header length
+        let overflowing = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", usize::MAX);
+        let e = parse_request(overflowing.as_bytes()).expect_err("refused");
+        assert_eq!(e.status(), 413);
+
+        let unparsable = parse_request(b"POST / HTTP/1.1\r\nContent-Length: lots\r\n\r\n").expect_err("refused");
+        assert_eq!(unparsable.status(), 400);
+    }
+
+    #[test]
+    fn responses_carry_their_length() {
+        let response = Response::json(404, &"missing");
+        let text = String::from_utf8(response.to_bytes()).expect("utf8 response");
+        assert!(text.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(text.contains("Content-Length: 9\r\n"));
+        assert!(text.ends_with("\r\n\r\n\"missing\""));
+        assert_eq!(parse_response(&response.to_bytes()).expect("valid response"), (404, b"\"missing\"".to_vec()));
+    }
+}
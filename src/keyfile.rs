@@ -0,0 +1,132 @@
+use libp2p::identity::{self, ed25519};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+pub const KEY_FILE: &str = "node.key";
+
+// The node's ed25519 keypair, stored as its 64 byte encoding (secret then public key). It's
+// created on first run, so the peer id stays the same across restarts.
+pub fn load_or_create<P: AsRef<Path>>(path: P) -> io::Result<identity::Keypair> {
+    let path = path.as_ref();
+    match OpenOptions::new().read(true).open(path) {
+        Ok(file) => read(path, file),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => create(path),
+        Err(e) => Err(e),
+    }
+}
+
+// For commands that sign as the node: they must never make up a new identity.
+pub fn load<P: AsRef<Path>>(path: P) -> io::Result<identity::Keypair> {
+    let path = path.as_ref();
+    match OpenOptions::new().read(true).open(path) {
+        Ok(file) => read(path, file),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no key at {}, run `key generate` first", path.display()),
+        )),
+        Err(e) => Err(e),
+    }
+}
+
+fn read(path: &Path, mut file: fs::File) -> io::Result<identity::Keypair> {
+    check_permissions(path, &file)?;
+
+    let mut bytes = vec![];
+    file.read_to_end(&mut bytes)?;
+    let keypair = ed25519::Keypair::decode(&mut bytes).map_err(|e| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("{} is not an ed25519 keypair: {}", path.display(), e))
+    })?;
+
+    Ok(identity::Keypair::Ed25519(keypair))
+}
+
+// Fails if `path` already exists, so a key is never overwritten by accident.
+pub fn create(path: &Path) -> io::Result<identity::Keypair> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let keypair = ed25519::Keypair::generate();
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    file.write_all(&keypair.encode())?;
+    file.sync_all()?;
+
+    Ok(identity::Keypair::Ed25519(keypair))
+}
+
+// Anyone who can read the key can impersonate the node, so it has to be private to its owner.
+#[cfg(unix)]
+fn check_permissions(path: &Path, file: &fs::File) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = file.metadata()?.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is accessible by other users (mode {:o}), chmod it to 600", path.display(), mode & 0o777),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path, _file: &fs::File) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::PeerId;
+    use std::path::PathBuf;
+
+    fn temp_key(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("blockchain-key-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join(KEY_FILE)
+    }
+
+    #[test]
+    fn key_is_created_once_and_reloaded() {
+        let path = temp_key("reload");
+        let created = load_or_create(&path).expect("can create key");
+        let loaded = load_or_create(&path).expect("can load key");
+        assert_eq!(PeerId::from(created.public()), PeerId::from(loaded.public()));
+
+        fs::write(&path, b"not a key").expect("can overwrite key");
+        assert_eq!(load_or_create(&path).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn loading_never_creates_a_key() {
+        let path = temp_key("load");
+        assert_eq!(load(&path).err().map(|e| e.kind()), Some(io::ErrorKind::NotFound));
+        assert!(!path.exists());
+
+        let created = create(&path).expect("can create key");
+        let loaded = load(&path).expect("can load key");
+        assert_eq!(PeerId::from(created.public()), PeerId::from(loaded.public()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn readable_keys_are_refused() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = temp_key("permissions");
+        load_or_create(&path).expect("can create key");
+        assert_eq!(fs::metadata(&path).expect("key exists").permissions().mode() & 0o777, 0o600);
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).expect("can chmod key");
+        assert_eq!(load_or_create(&path).err().map(|e| e.kind()), Some(io::ErrorKind::PermissionDenied));
+    }
+}
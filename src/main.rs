@@ -3,35 +3,105 @@ use libp2p::{
     futures::StreamExt,
     mplex,
     noise::{Keypair, NoiseConfig, X25519Spec},
-    swarm::{Swarm, SwarmBuilder},
+    swarm::{Swarm, SwarmBuilder, SwarmEvent},
     tcp::TokioTcpConfig,
+    PeerId,
     Transport,
 };
 use tokio::{
-    io::{stdin, AsyncBufReadExt, BufReader},
+    net::TcpListener,
     select, spawn,
     sync::mpsc,
-    time::sleep,
+    time::{interval, sleep},
 };
-use pretty_env_logger;
 use log::{info, error};
+use clap::Parser;
+use std::collections::HashSet;
 use std::time::Duration;
 mod p2p;
 mod blockchain;
+mod state;
+mod utxo;
+mod merkle;
+mod pow;
+mod events;
+mod blocktree;
+mod storage;
+mod mempool;
+mod wire;
+mod sync;
+mod downloader;
+mod keyfile;
+mod http;
+mod rpc;
+mod rest;
+mod ws;
+mod config;
+mod cli;
+mod repl;
+mod miner;
 
 
 
 #[tokio::main]
 async fn main() {
-    pretty_env_logger::init();
+    let cli = cli::Cli::parse();
+    let config = match cli.load_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let log_level = std::env::var("RUST_LOG").unwrap_or_else(|_| config.log_level.clone());
+    pretty_env_logger::formatted_builder().parse_filters(&log_level).init();
+
+    let result = match &cli.command {
+        None | Some(cli::Command::Node(cli::NodeCommand::Run)) => {
+            run_node(config).await;
+            Ok(())
+        }
+        Some(cli::Command::Init) => cli::init(&cli, &config),
+        Some(cli::Command::Key(cli::KeyCommand::Generate { force })) => cli::generate_key(&config, *force),
+        Some(cli::Command::Chain(cli::ChainCommand::Export { file })) => cli::export_chain(&config, file),
+        Some(cli::Command::Chain(cli::ChainCommand::Import { file })) => cli::import_chain(&config, file),
+        Some(cli::Command::Send { recipient, amount, fee }) => cli::send(&config, recipient.clone(), *amount, *fee).await,
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+// Binds one of the local APIs, unless its address is empty.
+async fn bind_api(name: &str, addr: &str) -> Option<TcpListener> {
+    if addr.is_empty() {
+        return None;
+    }
+    match TcpListener::bind(addr).await {
+        Ok(listener) => {
+            info!("{} listening on {}", name, addr);
+            Some(listener)
+        }
+        Err(e) => {
+            error!("can't start {} on {}: {}", name, addr, e);
+            None
+        }
+    }
+}
 
+async fn run_node(config: config::Config) {
+    let keys = keyfile::load_or_create(config.key_path()).expect("can load node key");
+    if p2p::KEYS.set(keys).is_err() {
+        panic!("node keys are only loaded once");
+    }
     info!("Peer Id: {}", p2p::PEER_ID.clone());
 
-    let (response_sender, mut response_rcv) = mpsc::unbounded_channel();
     let (init_sender, mut init_rcv) = mpsc::unbounded_channel();
 
     let auth_keys = Keypair::<X25519Spec>::new()
-        .into_authentic(&p2p::KEYS)
+        .into_authentic(p2p::keys())
         .expect("must be able to create auth keys");
 
     let transp = TokioTcpConfig::new()
@@ -40,7 +110,14 @@ async fn main() {
         .multiplex(mplex::MplexConfig::new())
         .boxed();
 
-    let behaviour = p2p::AppBehaviour::new(blockchain::App::new(), response_sender, init_sender.clone()).await;
+    info!("Ledger mode: {:?}", config.ledger_mode);
+
+    let store = storage::BlockStore::open(&config.data_dir).expect("can open block store");
+    let app = blockchain::App::with_store(config.ledger_mode, config.difficulty, Box::new(store))
+        .expect("can load chain from block store");
+
+    let (mined_sender, mut mined_rcv) = mpsc::unbounded_channel();
+    let behaviour = p2p::AppBehaviour::new(app, mined_sender).await;
 
     let mut swarm = SwarmBuilder::new(transp, behaviour,*p2p::PEER_ID)
         .executor(Box::new(|fut| {
@@ -48,33 +125,80 @@ async fn main() {
         }))
         .build();
 
-    let mut stdin = BufReader::new(stdin()).lines();
+    for addr in &config.network.listen_addrs {
+        Swarm::listen_on(
+            &mut swarm,
+            addr
+                .parse()
+                .expect("can get a local socket"),
+        )
+        .expect("swarm must be able to be started");
+    }
 
-    Swarm::listen_on(
-        &mut swarm,
-        "/ip4/0.0.0.0/tcp/0"
-            .parse()
-            .expect("can get a local socket"),
-    )
-    .expect("swarm must be able to be started");
+    p2p::bootstrap(&mut swarm, &config.network.bootstrap_peers);
+    let mut discovery = interval(Duration::from_secs(60));
+    let mut mining = interval(Duration::from_secs(config.mining.interval_secs.max(1)));
+    let mut mining_enabled = config.mining.enabled; // `mine start|stop` switches it
+    if mining_enabled {
+        info!("Mining a block every {} seconds", config.mining.interval_secs);
+    }
 
+    // The senders stay alive even if a server doesn't start, so the receivers never report
+    // a closed channel.
+    let (rpc_sender, mut rpc_rcv) = mpsc::unbounded_channel();
+    if let Some(listener) = bind_api("JSON-RPC", &config.api.rpc_addr).await {
+        spawn(rpc::serve(listener, rpc_sender.clone()));
+    }
+    let (rest_sender, mut rest_rcv) = mpsc::unbounded_channel();
+    if let Some(listener) = bind_api("REST API", &config.api.rest_addr).await {
+        spawn(rest::serve(listener, rest_sender.clone()));
+    }
+    if let Some(listener) = bind_api("WebSocket subscriptions", &config.api.ws_addr).await {
+        let chain_events = swarm.behaviour_mut().app.events.subscribe();
+        spawn(ws::serve(listener, chain_events));
+    }
+
+    let init_delay = Duration::from_secs(config.init_delay_secs);
     spawn(async move {
-        sleep(Duration::from_secs(1)).await;
+        sleep(init_delay).await;
         info!("sending init event");
         init_sender.send(true).expect("can send init event");
     });
 
+    // like the API senders, this one outlives the REPL thread, which ends on Ctrl-D
+    let (input_sender, mut input_rcv) = mpsc::unbounded_channel();
+    repl::spawn(config.data_dir.join(repl::HISTORY_FILE), input_sender.clone());
+
     loop {
         let evt = {
             select! {
-                line = stdin.next_line() => Some(p2p::EventType::Input(line.expect("can get line").expect("can read line from stdin"))),
-                response = response_rcv.recv() => {
-                    Some(p2p::EventType::LocalChainResponse(response.expect("Response exists.")))
-                },
+                line = input_rcv.recv() => line.map(p2p::EventType::Input),
                 _init = init_rcv.recv() => {
                     Some(p2p::EventType::Init)
                 }
+                call = rpc_rcv.recv() => call.map(p2p::EventType::Rpc),
+                call = rest_rcv.recv() => call.map(p2p::EventType::Rest),
+                mined = mined_rcv.recv() => mined.map(|(id, block)| p2p::EventType::Mined(id, block)),
+                _ = discovery.tick() => {
+                    swarm.behaviour_mut().discover_peers();
+                    None
+                },
+                _ = mining.tick(), if mining_enabled => {
+                    // a block mined on a stale tip would just be orphaned
+                    let behaviour = swarm.behaviour_mut();
+                    if !behaviour.miner.is_mining_periodically() && !behaviour.downloader.is_syncing() {
+                        let template = p2p::block_template(&behaviour.app);
+                        behaviour.miner.start(template, None);
+                    }
+                    None
+                },
                 event = swarm.select_next_some() => {
+                    if let SwarmEvent::ConnectionEstablished { peer_id, num_established, .. } = &event {
+                        if num_established.get() == 1 {
+                            let peer = peer_id.to_string();
+                            swarm.behaviour_mut().app.events.emit(events::ChainEvent::PeerConnected { peer });
+                        }
+                    }
                     info!("Unhandled Swarm Event: {:?}", event);
                     None
                 },
@@ -85,39 +209,35 @@ async fn main() {
             match event {
                 p2p::EventType::Init => {
                     let peers = p2p::get_list_peers(&swarm);
-                    swarm.behaviour_mut().app.add_genesis_block();
 
                     info!("connected nodes: {}", peers.len());
-                    if !peers.is_empty() {
-                        let req = p2p::LocalChainRequest {
-                            from_peer_id: peers
-                                .iter()
-                                .last()
-                                .expect("at least one peer")
-                                .to_string(),
-                        };
-
-                        let json = serde_json::to_string(&req).expect("can jsonify request");
-                        swarm
-                            .behaviour_mut()
-                            .floodsub
-                            .publish(p2p::CHAIN_TOPIC.clone(), json.as_bytes());
+                    let peers: HashSet<PeerId> = swarm.behaviour().mdns.discovered_nodes().cloned().collect();
+                    for peer in peers {
+                        swarm.behaviour_mut().request_tip(&peer);
                     }
                 },
-                p2p::EventType::LocalChainResponse(resp) => {
-                    let json = serde_json::to_string(&resp).expect("can jsonify response");
-                    swarm
-                        .behaviour_mut()
-                        .floodsub
-                        .publish(p2p::CHAIN_TOPIC.clone(), json.as_bytes());
+                p2p::EventType::Input(line) => {
+                    if !repl::handle(&line, &mut swarm, &mut mining_enabled) {
+                        break;
+                    }
                 },
-                p2p::EventType::Input(line) => match line.as_str() {
-                    "ls p" => p2p::handle_print_peers(&swarm),
-                    cmd if cmd.starts_with("ls c") => p2p::handle_print_chain(&swarm),
-                    cmd if cmd.starts_with("create b") => p2p::handle_create_block(cmd, &mut swarm),
-                    _ => error!("unknown command"),
+                p2p::EventType::Rpc(call) => rpc::handle(call, &mut swarm),
+                p2p::EventType::Rest(call) => rest::handle(call, &swarm),
+                p2p::EventType::Mined(id, block) => {
+                    let behaviour = swarm.behaviour_mut();
+                    if let Some(job) = behaviour.miner.take(id) {
+                        let added = p2p::add_mined_block(behaviour, block);
+                        job.finish(added.as_ref());
+                    }
                 },
             }
         }
+
+        // whatever replaced the tip, blocks still being mined could no longer extend it
+        let behaviour = swarm.behaviour_mut();
+        let tip = behaviour.app.latest_block().hash.clone();
+        behaviour.miner.retain(&tip, mining_enabled);
     }
+
+    info!("Shutting down");
 }
\ No newline at end of file
@@ -0,0 +1,335 @@
+use super::blockchain::{Block, Transaction};
+use super::state::State;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::fmt;
+
+pub const MAX_MEMPOOL_SIZE: usize = 1000;
+pub const MAX_BLOCK_TRANSACTIONS: usize = 100; // coinbase not included
+
+#[derive(Debug, PartialEq)]
+pub enum MempoolError {
+    Known,
+    Coinbase,
+    Malformed,
+    BadSignature,
+    BadNonce { expected: u64, got: u64 },
+    Overspend { available: u64, required: u64 },
+    Full { min_fee: u64 },
+}
+
+impl fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MempoolError::Known => write!(f, "transaction is already pending"),
+            MempoolError::Coinbase => write!(f, "coinbase transactions can't be submitted"),
+            MempoolError::Malformed => write!(f, "transaction is malformed"),
+            MempoolError::BadSignature => write!(f, "signature doesn't match the sender"),
+            MempoolError::BadNonce { expected, got } => write!(f, "nonce {} used, expected {}", got, expected),
+            MempoolError::Overspend { available, required } => {
+                write!(f, "spends {} but only {} is available", required, available)
+            }
+            MempoolError::Full { min_fee } => write!(f, "mempool is full, a fee above {} is needed", min_fee),
+        }
+    }
+}
+
+impl MempoolError {
+    // The transaction itself is at fault, so no later chain state will let it in.
+    pub fn is_permanent(&self) -> bool {
+        matches!(self, MempoolError::Coinbase | MempoolError::Malformed | MempoolError::BadSignature)
+    }
+}
+
+// Signed transactions waiting to be mined. Every sender's pending transactions use consecutive
+// nonces following its account nonce, and their amounts plus fees fit into its balance, so any
+// nonce-ordered prefix of them can go into the next block.
+pub struct Mempool {
+    transactions: HashMap<String, Transaction>, // by hash
+    by_sender: HashMap<String, BTreeMap<u64, String>>, // nonce -> hash
+    max_size: usize,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Mempool::new(MAX_MEMPOOL_SIZE)
+    }
+}
+
+impl Mempool {
+    pub fn new(max_size: usize) -> Mempool {
+        Mempool {
+            transactions: HashMap::new(),
+            by_sender: HashMap::new(),
+            max_size,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.transactions.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &str) -> Option<&Transaction> {
+        self.transactions.get(hash)
+    }
+
+    pub fn pending_count(&self, sender: &str) -> usize {
+        self.by_sender.get(sender).map_or(0, |nonces| nonces.len())
+    }
+
+    // Admits `tx` against the account state at the tip. Returns its hash.
+    pub fn add(&mut self, tx: Transaction, state: &State) -> Result<String, MempoolError> {
+        let hash = tx.hash();
+        if self.contains(&hash) {
+            return Err(MempoolError::Known);
+        } else if tx.is_coinbase() {
+            return Err(MempoolError::Coinbase);
+        } else if !tx.is_well_formed() {
+            return Err(MempoolError::Malformed);
+        } else if !tx.verify_signature() {
+            return Err(MempoolError::BadSignature);
+        }
+
+        let expected = state.nonce(&tx.sender) + self.pending_count(&tx.sender) as u64;
+        if tx.nonce != expected {
+            return Err(MempoolError::BadNonce { expected, got: tx.nonce });
+        }
+
+        let available = state.balance(&tx.sender).saturating_sub(self.pending_spend(&tx.sender));
+        let required = tx.amount.saturating_add(tx.fee);
+        if required > available {
+            return Err(MempoolError::Overspend { available, required });
+        }
+
+        if self.len() >= self.max_size {
+            self.evict_for(&tx.sender, tx.fee)?;
+        }
+
+        self.by_sender
+            .entry(tx.sender.clone())
+            .or_default()
+            .insert(tx.nonce, hash.clone());
+        self.transactions.insert(hash.clone(), tx);
+        Ok(hash)
+    }
+
+    // Highest fees first, but never a sender's transaction before the ones with lower nonces.
+    pub fn select(&self, limit: usize) -> Vec<Transaction> {
+        let mut queues: HashMap<&str, Vec<&Transaction>> = HashMap::new();
+        for (sender, nonces) in &self.by_sender {
+            // reversed, so popping yields the lowest nonce
+            let queue = nonces.values().rev().map(|hash| &self.transactions[hash]).collect();
+            queues.insert(sender.as_str(), queue);
+        }
+
+        let mut ready: BinaryHeap<(u64, &str)> = queues
+            .iter()
+            .filter_map(|(sender, queue)| queue.last().map(|tx| (tx.fee, *sender)))
+            .collect();
+
+        let mut selected = vec![];
+        while selected.len() < limit {
+            let (_, sender) = match ready.pop() {
+                Some(next) => next,
+                None => break,
+            };
+            let queue = queues.get_mut(sender).expect("ready senders have a queue");
+            selected.push(queue.pop().expect("ready senders have a transaction").clone());
+            if let Some(next) = queue.last() {
+                ready.push((next.fee, sender));
+            }
+        }
+
+        selected
+    }
+
+    pub fn remove_included(&mut self, block: &Block) {
+        for tx in &block.transactions {
+            self.remove(&tx.hash());
+        }
+    }
+
+    // Drops whatever no longer fits the state at the tip, e.g. after a reorg.
+    pub fn revalidate(&mut self, state: &State) {
+        let mut stale = vec![];
+        for (sender, nonces) in &self.by_sender {
+            let mut expected = state.nonce(sender);
+            let mut available = state.balance(sender);
+            for (nonce, hash) in nonces {
+                let tx = &self.transactions[hash];
+                let required = tx.amount.saturating_add(tx.fee);
+                if *nonce == expected && required <= available {
+                    expected += 1;
+                    available -= required;
+                } else {
+                    stale.push(hash.clone());
+                }
+            }
+        }
+
+        for hash in stale {
+            self.remove(&hash);
+        }
+    }
+
+    fn remove(&mut self, hash: &str) -> Option<Transaction> {
+        let tx = self.transactions.remove(hash)?;
+        if let Some(nonces) = self.by_sender.get_mut(&tx.sender) {
+            nonces.remove(&tx.nonce);
+            if nonces.is_empty() {
+                self.by_sender.remove(&tx.sender);
+            }
+        }
+
+        Some(tx)
+    }
+
+    fn pending_spend(&self, sender: &str) -> u64 {
+        self.by_sender
+            .get(sender)
+            .map(|nonces| {
+                nonces
+                    .values()
+                    .map(|hash| &self.transactions[hash])
+                    .map(|tx| tx.amount.saturating_add(tx.fee))
+                    .sum()
+            })
+            .unwrap_or(0)
+    }
+
+    // Makes room by dropping the cheapest transaction that is last in its sender's sequence,
+    // so the remaining nonces stay consecutive. Fails unless `fee` beats it. The incoming
+    // sender's own queue is left alone: its nonce and balance were checked against it.
+    fn evict_for(&mut self, sender: &str, fee: u64) -> Result<(), MempoolError> {
+        let cheapest = self
+            .by_sender
+            .iter()
+            .filter(|(other, _)| other.as_str() != sender)
+            .filter_map(|(_, nonces)| nonces.values().next_back())
+            .map(|hash| &self.transactions[hash])
+            .min_by_key(|tx| tx.fee)
+            .map(|tx| (tx.hash(), tx.fee));
+
+        match cheapest {
+            Some((hash, min_fee)) if min_fee < fee => {
+                self.remove(&hash);
+                Ok(())
+            }
+            Some((_, min_fee)) => Err(MempoolError::Full { min_fee }),
+            None => Err(MempoolError::Full { min_fee: 0 }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::BLOCK_REWARD;
+    use libp2p::{identity, PeerId};
+
+    fn funded(keys: &[&identity::Keypair]) -> State {
+        let mut block = Block::genesis_block();
+        block.transactions = keys
+            .iter()
+            .map(|keys| Transaction::coinbase(PeerId::from(keys.public()).to_string(), BLOCK_REWARD, 0))
+            .collect();
+
+        let mut state = State::new();
+        state.apply_block(&block).expect("rewards apply");
+        state
+    }
+
+    fn transfer(keys: &identity::Keypair, amount: u64, fee: u64, nonce: u64) -> Transaction {
+        let sender = PeerId::from(keys.public()).to_string();
+        let mut tx = Transaction::new(sender, String::from("bob"), amount, fee, nonce);
+        tx.sign(keys);
+        tx
+    }
+
+    #[test]
+    fn admission_checks_signature_nonce_and_balance() {
+        let keys = identity::Keypair::generate_ed25519();
+        let state = funded(&[&keys]);
+        let mut mempool = Mempool::default();
+
+        let mut forged = transfer(&keys, 10, 1, 0);
+        forged.amount = 20;
+        assert_eq!(mempool.add(forged, &state), Err(MempoolError::BadSignature));
+        assert_eq!(mempool.add(transfer(&keys, 10, 1, 1), &state), Err(MempoolError::BadNonce { expected: 0, got: 1 }));
+        assert!(MempoolError::BadSignature.is_permanent());
+        assert!(!MempoolError::BadNonce { expected: 0, got: 1 }.is_permanent());
+
+        let first = transfer(&keys, 30, 1, 0);
+        assert!(mempool.add(first.clone(), &state).is_ok());
+        assert_eq!(mempool.add(first, &state), Err(MempoolError::Known));
+        assert_eq!(
+            mempool.add(transfer(&keys, 30, 1, 1), &state),
+            Err(MempoolError::Overspend { available: BLOCK_REWARD - 31, required: 31 })
+        );
+        assert!(mempool.add(transfer(&keys, 10, 1, 1), &state).is_ok());
+        assert_eq!(mempool.len(), 2);
+    }
+
+    #[test]
+    fn selection_prefers_fees_but_keeps_nonce_order() {
+        let alice = identity::Keypair::generate_ed25519();
+        let carol = identity::Keypair::generate_ed25519();
+        let state = funded(&[&alice, &carol]);
+        let mut mempool = Mempool::default();
+
+        mempool.add(transfer(&alice, 1, 1, 0), &state).expect("admitted");
+        mempool.add(transfer(&alice, 1, 9, 1), &state).expect("admitted");
+        mempool.add(transfer(&carol, 1, 5, 0), &state).expect("admitted");
+
+        let fees: Vec<u64> = mempool.select(10).iter().map(|tx| tx.fee).collect();
+        assert_eq!(fees, vec![5, 1, 9]);
+        assert_eq!(mempool.select(1).len(), 1);
+    }
+
+    #[test]
+    fn full_pool_evicts_the_cheapest_and_blocks_drain_it() {
+        let alice = identity::Keypair::generate_ed25519();
+        let carol = identity::Keypair::generate_ed25519();
+        let state = funded(&[&alice, &carol]);
+        let mut mempool = Mempool::new(1);
+
+        mempool.add(transfer(&alice, 1, 2, 0), &state).expect("admitted");
+        assert_eq!(mempool.add(transfer(&carol, 1, 2, 0), &state), Err(MempoolError::Full { min_fee: 2 }));
+        let hash = mempool.add(transfer(&carol, 1, 3, 0), &state).expect("outbids the cheapest");
+        assert_eq!(mempool.len(), 1);
+
+        let mut block = Block::genesis_block();
+        block.transactions = vec![mempool.get(&hash).expect("pending").clone()];
+        mempool.remove_included(&block);
+        assert!(mempool.is_empty());
+    }
+
+    #[test]
+    fn eviction_never_leaves_a_nonce_gap() {
+        let alice = identity::Keypair::generate_ed25519();
+        let carol = identity::Keypair::generate_ed25519();
+        let state = funded(&[&alice, &carol]);
+        let mut mempool = Mempool::new(1);
+
+        mempool.add(transfer(&alice, 1, 1, 0), &state).expect("admitted");
+        assert_eq!(mempool.add(transfer(&alice, 1, 5, 1), &state), Err(MempoolError::Full { min_fee: 0 }));
+        let nonces: Vec<u64> = mempool.select(10).iter().map(|tx| tx.nonce).collect();
+        assert_eq!(nonces, vec![0]);
+
+        let mut mempool = Mempool::new(2);
+        mempool.add(transfer(&alice, 1, 1, 0), &state).expect("admitted");
+        mempool.add(transfer(&carol, 1, 2, 0), &state).expect("admitted");
+        mempool.add(transfer(&alice, 1, 5, 1), &state).expect("evicts carol instead");
+        let nonces: Vec<u64> = mempool.select(10).iter().map(|tx| tx.nonce).collect();
+        assert_eq!(nonces, vec![0, 1]);
+        assert_eq!(mempool.pending_count(&PeerId::from(carol.public()).to_string()), 0);
+    }
+}
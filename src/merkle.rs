@@ -0,0 +1,135 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// Leaves and inner nodes are hashed with different prefixes so an inner node can't pass as a leaf.
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProofStep {
+    pub hash: String,
+    pub side: Side, // which side the sibling sits on
+}
+
+// Inclusion proof for one transaction hash, from the leaf up to the root.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MerkleProof {
+    pub leaf: String,
+    pub index: usize,
+    pub steps: Vec<ProofStep>,
+}
+
+fn hash_leaf(leaf: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(leaf.as_bytes());
+    hasher.finalize().as_slice().to_owned()
+}
+
+fn hash_node(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().as_slice().to_owned()
+}
+
+// Pairs up a level, repeating the last node when the count is odd.
+fn next_level(level: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    level
+        .chunks(2)
+        .map(|pair| hash_node(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+        .collect()
+}
+
+pub fn merkle_root(leaves: &[String]) -> String {
+    if leaves.is_empty() {
+        return hex::encode([0u8; 32]);
+    }
+
+    let mut level: Vec<Vec<u8>> = leaves.iter().map(|leaf| hash_leaf(leaf)).collect();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+
+    hex::encode(&level[0])
+}
+
+pub fn merkle_proof(leaves: &[String], index: usize) -> Option<MerkleProof> {
+    let leaf = leaves.get(index)?.clone();
+
+    let mut steps = vec![];
+    let mut level: Vec<Vec<u8>> = leaves.iter().map(|leaf| hash_leaf(leaf)).collect();
+    let mut position = index;
+    while level.len() > 1 {
+        let step = match position % 2 {
+            0 => {
+                let sibling = level.get(position + 1).unwrap_or(&level[position]);
+                ProofStep { hash: hex::encode(sibling), side: Side::Right }
+            }
+            _ => ProofStep { hash: hex::encode(&level[position - 1]), side: Side::Left },
+        };
+        steps.push(step);
+
+        level = next_level(&level);
+        position /= 2;
+    }
+
+    Some(MerkleProof { leaf, index, steps })
+}
+
+// Only shows that `proof.leaf` is under `root`; whether `root` is in the chain is up to the caller.
+pub fn verify_proof(root: &str, proof: &MerkleProof) -> bool {
+    let mut current = hash_leaf(&proof.leaf);
+    for step in &proof.steps {
+        let sibling = match hex::decode(&step.hash) {
+            Ok(sibling) => sibling,
+            Err(_) => return false,
+        };
+        current = match step.side {
+            Side::Left => hash_node(&sibling, &current),
+            Side::Right => hash_node(&current, &sibling),
+        };
+    }
+
+    hex::encode(current) == root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("tx{}", i)).collect()
+    }
+
+    #[test]
+    fn every_leaf_has_a_valid_proof() {
+        for n in 1..=7 {
+            let leaves = leaves(n);
+            let root = merkle_root(&leaves);
+            for i in 0..n {
+                let proof = merkle_proof(&leaves, i).expect("leaf exists");
+                assert!(verify_proof(&root, &proof), "leaf {} of {}", i, n);
+            }
+        }
+    }
+
+    #[test]
+    fn proof_fails_for_other_leaf_or_root() {
+        let leaves = leaves(4);
+        let root = merkle_root(&leaves);
+        let mut proof = merkle_proof(&leaves, 1).expect("leaf exists");
+
+        assert!(!verify_proof(&merkle_root(&leaves[..3]), &proof));
+        proof.leaf = String::from("forged");
+        assert!(!verify_proof(&root, &proof));
+        assert!(merkle_proof(&leaves, 4).is_none());
+    }
+}
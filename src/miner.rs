@@ -0,0 +1,122 @@
+use super::blockchain::{self, Block};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::task;
+
+// Told the block once it's on the chain, or None if it didn't get there, e.g. because the tip
+// moved while it was being mined.
+pub type Reply = Box<dyn FnOnce(Option<&Block>) + Send>;
+
+// Proof of work on a blocking thread, so the swarm loop keeps running. Dropping the job stops
+// the search, e.g. once the tip it builds on has been replaced.
+pub struct MiningJob {
+    id: u64,
+    parent: String,
+    cancelled: Arc<AtomicBool>,
+    reply: Option<Reply>, // None for periodic mining
+}
+
+impl MiningJob {
+    pub fn builds_on(&self, tip: &str) -> bool {
+        self.parent == tip
+    }
+
+    // `block` is what became of the mined block once it was added.
+    pub fn finish(mut self, block: Option<&Block>) {
+        if let Some(reply) = self.reply.take() {
+            reply(block);
+        }
+    }
+}
+
+impl Drop for MiningJob {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        if let Some(reply) = self.reply.take() {
+            reply(None);
+        }
+    }
+}
+
+// The jobs in progress: one for periodic mining, if it's on, and one per block asked for.
+// Mined blocks are sent to `mined` with their job's id.
+pub struct Miner {
+    jobs: Vec<MiningJob>,
+    next_id: u64,
+    mined: mpsc::UnboundedSender<(u64, Block)>,
+}
+
+impl Miner {
+    pub fn new(mined: mpsc::UnboundedSender<(u64, Block)>) -> Miner {
+        Miner { jobs: vec![], next_id: 0, mined }
+    }
+
+    pub fn start(&mut self, mut block: Block, reply: Option<Reply>) {
+        let id = self.next_id;
+        self.next_id += 1;
+        let cancelled = Arc::new(AtomicBool::new(false));
+        self.jobs.push(MiningJob { id, parent: block.header.prev_hash.clone(), cancelled: cancelled.clone(), reply });
+
+        let mined = self.mined.clone();
+        task::spawn_blocking(move || {
+            if let Some(hash) = blockchain::mine_block_until(&mut block.header, &cancelled) {
+                block.hash = hash;
+                // the loop may have stopped in the meantime
+                let _ = mined.send((id, block));
+            }
+        });
+    }
+
+    pub fn is_mining_periodically(&self) -> bool {
+        self.jobs.iter().any(|job| job.reply.is_none())
+    }
+
+    // The job that mined a block, unless it was dropped since.
+    pub fn take(&mut self, id: u64) -> Option<MiningJob> {
+        let index = self.jobs.iter().position(|job| job.id == id)?;
+        Some(self.jobs.remove(index))
+    }
+
+    // Drops the jobs whose block could no longer extend `tip`, and the periodic one if
+    // periodic mining was turned off.
+    pub fn retain(&mut self, tip: &str, periodic: bool) {
+        self.jobs.retain(|job| job.builds_on(tip) && (periodic || job.reply.is_some()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pow::DIFFICULTY_BITS;
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn requested_blocks_are_answered_once_mined_or_dropped() {
+        let (sender, mut mined) = mpsc::unbounded_channel();
+        let mut miner = Miner::new(sender);
+        let genesis = Block::genesis_block();
+        let template = || Block::template(&genesis, DIFFICULTY_BITS, vec![], vec![]);
+        let answers = Arc::new(Mutex::new(vec![]));
+        let reply = || -> Reply {
+            let answers = answers.clone();
+            Box::new(move |block: Option<&Block>| answers.lock().expect("not poisoned").push(block.map(|b| b.header.height)))
+        };
+
+        miner.start(template(), Some(reply()));
+        let (id, block) = mined.recv().await.expect("the job sends its block");
+        miner.take(id).expect("the job is still wanted").finish(Some(&block));
+        assert_eq!(*answers.lock().expect("not poisoned"), vec![Some(1)]);
+
+        // the tip moved on, so the block can't extend it any more
+        miner.start(template(), Some(reply()));
+        miner.retain("another tip", true);
+        assert_eq!(*answers.lock().expect("not poisoned"), vec![Some(1), None]);
+
+        miner.start(template(), None);
+        miner.retain(&genesis.hash, true);
+        assert!(miner.is_mining_periodically());
+        miner.retain(&genesis.hash, false);
+        assert!(!miner.is_mining_periodically());
+    }
+}
@@ -1,118 +1,357 @@
-use super::blockchain::{App, Block, Transaction};
+use super::blockchain::{App, Block, BlockStatus, LedgerMode, Transaction, BLOCK_REWARD};
+use super::utxo::UtxoTransaction;
+use super::mempool::MAX_BLOCK_TRANSACTIONS;
+use super::wire::{self, Message, SyncRequest, SyncResponse};
+use super::sync::{self, SyncCodec};
+use super::rpc::RpcCall;
+use super::rest::RestCall;
+use super::downloader::{Downloader, BLOCKS_PER_REQUEST, HEADERS_PER_REQUEST};
+use super::miner::{Miner, Reply};
 use libp2p::{
-    floodsub::{Floodsub, FloodsubEvent, Topic},
+    gossipsub::{
+        Gossipsub, GossipsubConfigBuilder, GossipsubEvent, GossipsubMessage, IdentTopic as Topic, MessageAcceptance,
+        MessageAuthenticity, MessageId, PeerScoreParams, PeerScoreThresholds, TopicScoreParams, ValidationMode,
+    },
+    identify::{Identify, IdentifyConfig, IdentifyEvent},
     identity,
+    kad::{store::MemoryStore as KadStore, Kademlia, KademliaConfig, KademliaEvent},
     mdns::{Mdns, MdnsEvent},
+    multiaddr::Protocol,
+    request_response::{RequestResponse, RequestResponseEvent, RequestResponseMessage},
     swarm::{NetworkBehaviourEventProcess, Swarm},
+    Multiaddr,
     NetworkBehaviour, 
     PeerId,
 };
 use log::{error, info};
-use once_cell::sync::Lazy;
-use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use once_cell::sync::{Lazy, OnceCell};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Duration;
 use tokio::sync::mpsc;
 
-pub static KEYS: Lazy<identity::Keypair> = Lazy::new(|| identity::Keypair::generate_ed25519());
-pub static PEER_ID: Lazy<PeerId> = Lazy::new(|| PeerId::from(KEYS.public())); // PEER_ID is derived from pub key
-pub static CHAIN_TOPIC: Lazy<Topic> = Lazy::new(|| Topic::new("chains"));
+pub static KEYS: OnceCell<identity::Keypair> = OnceCell::new(); // set from the key file at startup
+pub static PEER_ID: Lazy<PeerId> = Lazy::new(|| PeerId::from(keys().public())); // PEER_ID is derived from pub key
 pub static BLOCK_TOPIC: Lazy<Topic> = Lazy::new(|| Topic::new("blocks"));
+pub static TX_TOPIC: Lazy<Topic> = Lazy::new(|| Topic::new("transactions"));
 
-pub enum EventType { // handles incoming msg, lazy initialisation and keyboard input. 
-    LocalChainResponse(ChainResponse), 
+const SEEN_TRANSACTIONS: usize = 10_000;
+
+// Applied to the square of a peer's invalid message count, at the default topic weight of 0.5.
+// Three invalid blocks take it below the graylist threshold (-80), after which it's ignored.
+const INVALID_MESSAGE_WEIGHT: f64 = -20.0;
+
+pub enum EventType { // handles lazy initialisation, keyboard input and API calls.
     Input(String),
     Init,
+    Rpc(RpcCall),
+    Rest(RestCall),
+    Mined(u64, Block), // by a mining job in the background, with the job's id
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct LocalChainRequest { //Triggers interaction. Sending this will trigger a node to send their chain back. 
-    pub from_peer_id: String,
+// Remembers the most recent transaction hashes, so a payload that floods back to us
+// through several peers is validated once.
+pub struct SeenCache {
+    capacity: usize,
+    hashes: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl Default for SeenCache {
+    fn default() -> Self {
+        SeenCache::with_capacity(SEEN_TRANSACTIONS)
+    }
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct ChainResponse { // Use to send a list of blocks and expect when we receive one. 
-    pub blocks: Vec<Block>,
-    pub receiver: String,
+impl SeenCache {
+    pub fn with_capacity(capacity: usize) -> SeenCache {
+        SeenCache { capacity: capacity.max(1), hashes: HashSet::new(), order: VecDeque::new() }
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.hashes.contains(hash)
+    }
+
+    // Returns false if the hash was seen before.
+    pub fn insert(&mut self, hash: String) -> bool {
+        if self.hashes.contains(&hash) {
+            return false;
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.hashes.remove(&oldest);
+            }
+        }
+
+        self.order.push_back(hash.clone());
+        self.hashes.insert(hash)
+    }
 }
 
 #[derive(NetworkBehaviour)]
 pub struct AppBehaviour {
-    pub floodsub: Floodsub,
-    pub mdns: Mdns,
+    pub gossipsub: Gossipsub,
+    pub mdns: Mdns, // finds peers on the local network
+    pub kademlia: Kademlia<KadStore>, // finds peers anywhere, starting from the bootstrap peers
+    pub identify: Identify, // tells kademlia which addresses inbound peers listen on
+    pub sync: RequestResponse<SyncCodec>, // headers and blocks are requested from one peer, not broadcast
     #[behaviour(ignore)]
-    pub response_sender: mpsc::UnboundedSender<ChainResponse>,
+    pub app: App,
     #[behaviour(ignore)]
-    pub init_sender: mpsc::UnboundedSender<bool>,
+    pub seen_transactions: SeenCache,
     #[behaviour(ignore)]
-    pub app: App,
+    pub downloader: Downloader,
+    #[behaviour(ignore)]
+    pub miner: Miner,
 }
 
 impl AppBehaviour {
-    pub async fn new(
-        app: App, 
-        response_sender: mpsc::UnboundedSender<ChainResponse>, 
-        init_sender: mpsc::UnboundedSender<bool>,
-    ) -> Self {
+    pub async fn new(app: App, mined: mpsc::UnboundedSender<(u64, Block)>) -> Self {
         let mut behaviour = Self {
             app,
-            floodsub: Floodsub::new(*PEER_ID),
+            gossipsub: new_gossipsub(),
+            kademlia: new_kademlia(),
+            identify: Identify::new(IdentifyConfig::new(String::from("/blockchain/1.0.0"), keys().public())),
             mdns: Mdns::new(Default::default())
                 .await
                 .expect("must be able to create mdns"),
-            response_sender,
-            init_sender,
+            sync: sync::new_behaviour(),
+            seen_transactions: SeenCache::default(),
+            downloader: Downloader::new(),
+            miner: Miner::new(mined),
         };
-        behaviour.floodsub.subscribe(CHAIN_TOPIC.clone());
-        behaviour.floodsub.subscribe(BLOCK_TOPIC.clone());
+        for topic in [&*BLOCK_TOPIC, &*TX_TOPIC] {
+            behaviour.gossipsub.subscribe(topic).expect("can subscribe to topic");
+        }
         
         behaviour
     }
+
+    pub fn publish(&mut self, topic: Topic, message: &Message) {
+        if let Err(e) = self.gossipsub.publish(topic, wire::encode(message)) {
+            error!("Could not publish message: {:?}", e);
+        }
+    }
+
+    // A lookup for a random id walks the DHT, filling the routing table on the way.
+    pub fn discover_peers(&mut self) {
+        self.kademlia.get_closest_peers(PeerId::random());
+    }
+
+    // Asks for the peer's tip; if it's ahead of us, the downloader takes it from there.
+    pub fn request_tip(&mut self, peer: &PeerId) {
+        self.sync.send_request(peer, SyncRequest::Tip);
+    }
+
+    fn answer_sync(&self, request: SyncRequest) -> SyncResponse {
+        match request {
+            SyncRequest::Tip => {
+                let tip = self.app.latest_block();
+                SyncResponse::Tip { height: tip.header.height, hash: tip.hash.clone() }
+            }
+            SyncRequest::Headers { locator } => SyncResponse::Headers(self.app.headers_after(&locator, HEADERS_PER_REQUEST)),
+            SyncRequest::Blocks(hashes) => SyncResponse::Blocks(
+                hashes
+                    .iter()
+                    .take(BLOCKS_PER_REQUEST)
+                    .filter_map(|hash| self.app.tree.get(hash).cloned())
+                    .collect(),
+            ),
+        }
+    }
+
+    pub fn publish_transaction(&mut self, tx: &Transaction) {
+        if self.seen_transactions.insert(tx.hash()) {
+            self.publish(TX_TOPIC.clone(), &Message::Transaction(tx.clone()));
+        }
+    }
+
+    // Blocks are only forwarded once they're connected; invalid ones count against the sender.
+    fn handle_block(&mut self, block: Block, source: &PeerId) -> MessageAcceptance {
+        info!("received new block from {}", source);
+        match self.app.add_block_to_chain(block) {
+            BlockStatus::Extended | BlockStatus::Reorg | BlockStatus::SideChain => MessageAcceptance::Accept,
+            BlockStatus::Orphan => {
+                // we're missing its ancestors, so catch up with whoever sent it
+                self.request_tip(source);
+                MessageAcceptance::Ignore
+            }
+            BlockStatus::Known => MessageAcceptance::Ignore,
+            BlockStatus::Invalid => {
+                error!("{} sent an invalid block", source);
+                MessageAcceptance::Reject
+            }
+        }
+    }
+
+    // Only settled outcomes are remembered. A transaction turned away for its nonce or balance
+    // may be fine once we catch up, so a later copy of it is still looked at.
+    fn handle_transaction(&mut self, tx: Transaction, source: &PeerId) -> MessageAcceptance {
+        let hash = tx.hash();
+        if self.seen_transactions.contains(&hash) {
+            return MessageAcceptance::Ignore;
+        }
+        if self.app.mode != LedgerMode::Account {
+            return MessageAcceptance::Ignore;
+        }
+
+        match self.app.submit_transaction(tx) {
+            Ok(hash) => {
+                info!("Transaction {} from {} added to the mempool", hash, source);
+                self.seen_transactions.insert(hash);
+                MessageAcceptance::Accept
+            }
+            Err(e) if e.is_permanent() => {
+                error!("Transaction from {} rejected: {}", source, e);
+                self.seen_transactions.insert(hash);
+                MessageAcceptance::Reject
+            }
+            // may well be valid against another peer's view of the chain
+            Err(e) => {
+                info!("Transaction from {} not added: {}", source, e);
+                MessageAcceptance::Ignore
+            }
+        }
+    }
+}
+
+// Messages are identified by their content, so the same block or transaction relayed by
+// several peers (or published twice) is only processed once.
+fn message_id(message: &GossipsubMessage) -> MessageId {
+    MessageId::from(hex::encode(Sha256::digest(&message.data)))
+}
+
+pub fn keys() -> &'static identity::Keypair {
+    KEYS.get().expect("node keys are loaded at startup")
+}
+
+fn new_kademlia() -> Kademlia<KadStore> {
+    // our own protocol name keeps us out of the public IPFS DHT
+    let mut config = KademliaConfig::default();
+    config.set_protocol_name(&b"/blockchain/kad/1.0.0"[..]);
+    Kademlia::with_config(*PEER_ID, KadStore::new(*PEER_ID), config)
+}
+
+fn new_gossipsub() -> Gossipsub {
+    let config = GossipsubConfigBuilder::default()
+        .heartbeat_interval(Duration::from_secs(1))
+        .mesh_n(6)
+        .mesh_n_low(4)
+        .mesh_n_high(12)
+        .validation_mode(ValidationMode::Strict)
+        .validate_messages() // nothing is forwarded before `report_message_validation_result`
+        .message_id_fn(message_id)
+        .build()
+        .expect("gossipsub config is valid");
+    let mut gossipsub = Gossipsub::new(MessageAuthenticity::Signed(keys().clone()), config)
+        .expect("can create gossipsub");
+
+    let topic_params = TopicScoreParams {
+        invalid_message_deliveries_weight: INVALID_MESSAGE_WEIGHT,
+        invalid_message_deliveries_decay: 0.99,
+        // a small network can't promise a steady flow of blocks, so don't punish quiet peers
+        mesh_message_deliveries_weight: 0.0,
+        ..TopicScoreParams::default()
+    };
+    let mut topics = HashMap::new();
+    for topic in [&*BLOCK_TOPIC, &*TX_TOPIC] {
+        topics.insert(topic.hash(), topic_params.clone());
+    }
+    let params = PeerScoreParams { topics, ..PeerScoreParams::default() };
+    gossipsub
+        .with_peer_score(params, PeerScoreThresholds::default())
+        .expect("peer score parameters are valid");
+
+    gossipsub
 }
 
 impl NetworkBehaviourEventProcess<MdnsEvent> for AppBehaviour {
     fn inject_event(&mut self, event: MdnsEvent) {
         match event {
             MdnsEvent::Discovered(discovered_list) => {
+                let mut peers = HashSet::new();
                 for (peer, _addr) in discovered_list {
-                    self.floodsub.add_node_to_partial_view(peer);
+                    peers.insert(peer);
                 }
-            },
-            MdnsEvent::Expired(expired_list) => {
-                for (peer, _addr) in expired_list {
-                    if !self.mdns.has_node(&peer) {
-                        self.floodsub.remove_node_from_partial_view(&peer);
-                    }
+                // connecting for the tip request also lets gossipsub graft the peer
+                for peer in peers {
+                    self.request_tip(&peer);
                 }
             },
+            MdnsEvent::Expired(_) => {},
         }
     }
 }
 
-impl NetworkBehaviourEventProcess<FloodsubEvent> for AppBehaviour {
-    fn inject_event(&mut self, event: FloodsubEvent) {
-        if let FloodsubEvent::Message(msg) = event {
-            if let Ok(resp) = serde_json::from_slice::<ChainResponse>(&msg.data) {
-                if resp.receiver == PEER_ID.to_string() {
-                    info!("Response from {}:", msg.source);
-                    resp.blocks.iter().for_each(|r| info!("{:?}", r));
+impl NetworkBehaviourEventProcess<KademliaEvent> for AppBehaviour {
+    fn inject_event(&mut self, event: KademliaEvent) {
+        if let KademliaEvent::RoutingUpdated { peer, .. } = event {
+            info!("Kademlia added {} to the routing table", peer);
+            self.request_tip(&peer);
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<IdentifyEvent> for AppBehaviour {
+    fn inject_event(&mut self, event: IdentifyEvent) {
+        if let IdentifyEvent::Received { peer_id, info } = event {
+            for addr in info.listen_addrs {
+                self.kademlia.add_address(&peer_id, addr);
+            }
+        }
+    }
+}
 
-                    self.app.blocks = self.app.choose_chain(self.app.blocks.clone(), resp.blocks);
+impl NetworkBehaviourEventProcess<GossipsubEvent> for AppBehaviour {
+    fn inject_event(&mut self, event: GossipsubEvent) {
+        if let GossipsubEvent::Message { propagation_source, message_id, message } = event {
+            let acceptance = match wire::decode(&message.data) {
+                Ok(Message::Block(block)) => self.handle_block(block, &propagation_source),
+                Ok(Message::Transaction(tx)) => self.handle_transaction(tx, &propagation_source),
+                Err(e) => {
+                    error!("Dropping message from {}: {}", propagation_source, e);
+                    MessageAcceptance::Reject
                 }
-            } else if let Ok(resp) = serde_json::from_slice::<LocalChainRequest>(&msg.data) {
-                info!("sending local chain to {}", msg.source.to_string());
-                let peer_id = resp.from_peer_id;
-                if PEER_ID.to_string() == peer_id {
-                    if let Err(e) = self.response_sender.send(ChainResponse {
-                        blocks: self.app.blocks.clone(),
-                        receiver: msg.source.to_string(),
-                    }) {
-                        error!("error sending response via channel, {}", e);
+            };
+
+            if let Err(e) = self.gossipsub.report_message_validation_result(&message_id, &propagation_source, acceptance) {
+                error!("Could not forward message {}: {:?}", message_id, e);
+            }
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<SyncRequest, SyncResponse>> for AppBehaviour {
+    fn inject_event(&mut self, event: RequestResponseEvent<SyncRequest, SyncResponse>) {
+        match event {
+            RequestResponseEvent::Message { peer, message } => match message {
+                RequestResponseMessage::Request { request, channel, .. } => {
+                    let response = self.answer_sync(request);
+                    if self.sync.send_response(channel, response).is_err() {
+                        error!("{} hung up before its sync request was answered", peer);
                     }
                 }
-            } else if let Ok(block) = serde_json::from_slice::<Block>(&msg.data) {
-                info!("received new block from {}", msg.source.to_string());
-                self.app.add_block_to_chain(block);
+                RequestResponseMessage::Response { request_id, response } => match response {
+                    SyncResponse::Tip { height, hash } => {
+                        self.downloader.on_tip(&mut self.sync, peer, height, &hash, &self.app)
+                    }
+                    SyncResponse::Headers(headers) => {
+                        self.downloader.on_headers(&mut self.sync, request_id, headers, &self.app)
+                    }
+                    SyncResponse::Blocks(blocks) => {
+                        info!("Received {} blocks from {}", blocks.len(), peer);
+                        self.downloader.on_blocks(&mut self.sync, request_id, blocks, &mut self.app)
+                    }
+                },
+            },
+            RequestResponseEvent::OutboundFailure { peer, request_id, error } => {
+                error!("Sync request to {} failed: {:?}", peer, error);
+                self.downloader.on_failure(&mut self.sync, request_id, peer);
             }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                error!("Sync request from {} failed: {:?}", peer, error);
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
         }
     }
 }
@@ -125,47 +364,204 @@ pub fn get_list_peers(swarm: &Swarm<AppBehaviour>) -> Vec<String> {
     for peer in nodes {
         unique_peers.insert(peer);
     }
+    for (peer, _topics) in swarm.behaviour().gossipsub.all_peers() {
+        unique_peers.insert(peer);
+    }
 
     unique_peers.iter().map(|p| p.to_string()).collect()
 }
 
-pub fn handle_print_peers(swarm: &Swarm<AppBehaviour>) {
-    let peers = get_list_peers(swarm);
-    peers.iter().for_each(|p| info!("{}", p));
+// Splits `/ip4/.../tcp/.../p2p/<peer id>` into the address and the peer expected there.
+pub fn parse_peer_addr(addr: &str) -> Result<(Multiaddr, Option<PeerId>), String> {
+    let addr: Multiaddr = addr.parse().map_err(|e| format!("{} is not a multiaddr: {}", addr, e))?;
+    if addr.is_empty() {
+        return Err(String::from("peer address is empty"));
+    }
+    let peer = match addr.iter().last() {
+        Some(Protocol::P2p(hash)) => {
+            Some(PeerId::from_multihash(hash).map_err(|_| format!("{} has an invalid peer id", addr))?)
+        }
+        _ => None,
+    };
+
+    Ok((addr, peer))
 }
 
-pub fn handle_print_chain(swarm: &Swarm<AppBehaviour>) {
-    info!("Local Blockchain:");
+pub fn dial(swarm: &mut Swarm<AppBehaviour>, addr: &str) {
+    let (addr, peer) = match parse_peer_addr(addr) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
 
-    let pretty_json = serde_json::to_string_pretty(&swarm.behaviour().app.blocks).expect("can jsonify blocks");
-    
-    info!("{}", pretty_json);
-}
-
-pub fn handle_create_block(cmd: &str, swarm: &mut Swarm<AppBehaviour>) {
-    if let Some(num) = cmd.strip_prefix("create b") {
-        match num.trim().parse::<usize>() {
-            Ok(n) => {
-                let behaviour = swarm.behaviour_mut();
-
-                let latest_block = behaviour
-                    .app
-                    .blocks
-                    .last()
-                    .expect("there must be at least one block");
-
-                let block = Block::new(
-                    latest_block.id + 1,
-                    latest_block.header.clone(),
-                    vec![Transaction {}; n]
-                );
-
-                let json = serde_json::to_string(&block).expect("must be able to jsonify request");
-                behaviour.app.blocks.push(block);
-                info!("Broadcasting new block");
-                behaviour.floodsub.publish(BLOCK_TOPIC.clone(), json.as_bytes());
-            },
-            Err(n) => error!("Must enter a number with the create b command! {}", n)
+    if let Some(peer) = peer {
+        swarm.behaviour_mut().kademlia.add_address(&peer, addr.clone());
+    }
+    match swarm.dial_addr(addr.clone()) {
+        Ok(()) => info!("Dialing {}", addr),
+        Err(e) => error!("Could not dial {}: {:?}", addr, e),
+    }
+}
+
+// Dials every bootstrap peer and lets kademlia discover the rest of the network through them.
+pub fn bootstrap(swarm: &mut Swarm<AppBehaviour>, addrs: &[String]) {
+    for addr in addrs {
+        dial(swarm, addr);
+    }
+    if swarm.behaviour_mut().kademlia.bootstrap().is_err() {
+        info!("No bootstrap peers with a peer id, relying on mDNS");
+    }
+}
+
+// Starts mining a block on top of our tip, optionally with a transfer from this node. `reply`
+// gets the block once it's on the chain and broadcast; it's never called if the transfer is
+// refused, which is logged instead.
+pub fn create_block(behaviour: &mut AppBehaviour, transfer: Option<(String, u64, u64)>, reply: Reply) {
+    let block = match behaviour.app.mode {
+        LedgerMode::Account => {
+            if let Some((recipient, amount, fee)) = transfer {
+                if submit_transfer(&mut behaviour.app, recipient, amount, fee).is_none() {
+                    return;
+                }
+            }
+            create_account_block(&behaviour.app)
         }
+        LedgerMode::Utxo => match create_utxo_block(&behaviour.app, transfer) {
+            Some(block) => block,
+            None => return,
+        },
+    };
+
+    behaviour.miner.start(block, Some(reply));
+}
+
+// The block periodic mining works on: the mempool's best transactions and no transfer.
+pub fn block_template(app: &App) -> Block {
+    match app.mode {
+        LedgerMode::Account => create_account_block(app),
+        LedgerMode::Utxo => create_utxo_block(app, None).expect("a block without a transfer spends nothing"),
     }
-}
\ No newline at end of file
+}
+
+// Adds a block we mined and broadcasts it. Returns the block if it extended the chain.
+pub fn add_mined_block(behaviour: &mut AppBehaviour, block: Block) -> Option<Block> {
+    let message = Message::Block(block.clone());
+    if behaviour.app.add_block_to_chain(block.clone()) != BlockStatus::Extended {
+        return None;
+    }
+    info!("Broadcasting new block");
+    behaviour.publish(BLOCK_TOPIC.clone(), &message);
+    Some(block)
+}
+
+// Signs a transfer from this node and queues it in the mempool.
+pub fn submit_transfer(app: &mut App, recipient: String, amount: u64, fee: u64) -> Option<Transaction> {
+    let sender = PEER_ID.to_string();
+    let nonce = app.next_nonce(&sender);
+    let mut tx = Transaction::new(sender, recipient, amount, fee, nonce);
+    tx.sign(keys());
+
+    match app.submit_transaction(tx.clone()) {
+        Ok(hash) => {
+            info!("Transaction {} added to the mempool", hash);
+            Some(tx)
+        }
+        Err(e) => {
+            error!("Transaction rejected: {}", e);
+            None
+        }
+    }
+}
+
+fn create_account_block(app: &App) -> Block {
+    let miner = PEER_ID.to_string();
+    let mut transactions = app.mempool.select(MAX_BLOCK_TRANSACTIONS);
+
+    let latest_block = app.latest_block();
+
+    let fees: u64 = transactions.iter().map(|tx| tx.fee).sum();
+    transactions.insert(0, Transaction::coinbase(miner, BLOCK_REWARD + fees, latest_block.header.height + 1));
+
+    Block::template(latest_block, app.next_difficulty_target(), transactions, vec![])
+}
+
+fn create_utxo_block(app: &App, transfer: Option<(String, u64, u64)>) -> Option<Block> {
+    let mut transactions = vec![];
+    let mut fees = 0;
+
+    if let Some((recipient, amount, fee)) = transfer {
+        match app.utxos.create_transaction(keys(), recipient, amount, fee) {
+            Some(tx) => {
+                // the change output returns everything else, so the fee is exactly what was asked
+                transactions.push(tx);
+                fees = fee;
+            }
+            None => {
+                error!("Insufficient unspent outputs: {} available", app.utxos.balance(&PEER_ID.to_string()));
+                return None;
+            }
+        }
+    }
+
+    let latest_block = app.latest_block();
+    // the fee was covered by our own outputs, so this can't overflow
+    transactions.insert(0, UtxoTransaction::coinbase(PEER_ID.to_string(), BLOCK_REWARD + fees, latest_block.header.height + 1));
+
+    Some(Block::template(latest_block, app.next_difficulty_target(), vec![], transactions))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seen_hashes_are_suppressed_until_evicted() {
+        let mut seen = SeenCache::with_capacity(2);
+        assert!(seen.insert(String::from("a")));
+        assert!(!seen.insert(String::from("a")));
+        assert!(seen.contains("a"));
+
+        assert!(seen.insert(String::from("b")));
+        assert!(seen.insert(String::from("c")));
+        assert!(!seen.contains("a"), "the oldest hash makes room");
+        assert!(seen.contains("b") && seen.contains("c"));
+        assert!(seen.insert(String::from("a")));
+    }
+
+    #[test]
+    fn message_ids_depend_only_on_content() {
+        let message = |source: PeerId, sequence_number: u64, data: &[u8]| GossipsubMessage {
+            source: Some(source),
+            data: data.to_vec(),
+            sequence_number: Some(sequence_number),
+            topic: BLOCK_TOPIC.hash(),
+        };
+        let alice = PeerId::from(identity::Keypair::generate_ed25519().public());
+        let bob = PeerId::from(identity::Keypair::generate_ed25519().public());
+
+        let id = message_id(&message(alice, 1, b"block"));
+        assert_eq!(message_id(&message(bob, 1, b"block")), id, "relayed by another peer");
+        assert_eq!(message_id(&message(alice, 2, b"block")), id, "published twice");
+        assert_ne!(message_id(&message(alice, 1, b"other block")), id);
+    }
+
+    #[test]
+    fn peer_addresses_parse_with_or_without_a_peer_id() {
+        let peer = PeerId::from(identity::Keypair::generate_ed25519().public());
+
+        let (addr, id) = parse_peer_addr("/ip4/10.0.0.1/tcp/4001").expect("valid address");
+        assert_eq!(addr.to_string(), "/ip4/10.0.0.1/tcp/4001");
+        assert_eq!(id, None);
+
+        let (addr, id) = parse_peer_addr(&format!("/ip4/10.0.0.1/tcp/4001/p2p/{}", peer)).expect("valid address");
+        assert_eq!(addr.iter().count(), 3);
+        assert_eq!(id, Some(peer));
+
+        assert!(parse_peer_addr("10.0.0.1:4001").is_err());
+        assert!(parse_peer_addr("/ip4/10.0.0.1/tcp/port").is_err());
+        assert!(parse_peer_addr("/ip4/10.0.0.1/tcp/4001/p2p/not-a-peer").is_err());
+        assert!(parse_peer_addr("").is_err());
+    }
+}
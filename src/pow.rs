@@ -0,0 +1,178 @@
+use super::blockchain::Block;
+use serde::{Deserialize, Serialize};
+
+pub use self::u256::U256;
+
+#[allow(clippy::all)]
+mod u256 {
+    uint::construct_uint! {
+        pub struct U256(4);
+    }
+}
+
+// Compact "bits" form of the easiest allowed target: a hash needs 16 leading zero bits.
+// Chains start here and retargeting never goes easier.
+pub const DIFFICULTY_BITS: u32 = 0x1f00ffff;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DifficultyConfig {
+    pub retarget_interval: u32, // blocks between adjustments
+    pub target_block_time: i64, // seconds
+}
+
+impl Default for DifficultyConfig {
+    fn default() -> Self {
+        DifficultyConfig {
+            retarget_interval: 10,
+            target_block_time: 10,
+        }
+    }
+}
+
+// Expands compact bits (1 byte exponent, 3 byte mantissa) into the full 256-bit target.
+pub fn target_from_bits(bits: u32) -> U256 {
+    let exponent = bits >> 24;
+    let mantissa = U256::from(bits & 0x007fffff);
+
+    if exponent <= 3 {
+        mantissa >> (8 * (3 - exponent) as usize)
+    } else if exponent > 32 {
+        U256::MAX
+    } else {
+        mantissa << (8 * (exponent - 3) as usize)
+    }
+}
+
+pub fn bits_from_target(target: U256) -> u32 {
+    let mut size = (target.bits() as u32).div_ceil(8);
+    let mut compact = if size <= 3 {
+        target.low_u32() << (8 * (3 - size))
+    } else {
+        (target >> (8 * (size - 3) as usize)).low_u32()
+    };
+
+    // the top mantissa bit is a sign bit in this encoding, so shift it into the exponent
+    if compact & 0x00800000 != 0 {
+        compact >>= 8;
+        size += 1;
+    }
+
+    compact | (size << 24)
+}
+
+// A hash, read as a big-endian number, must not exceed the target.
+pub fn meets_target(hash: &[u8], bits: u32) -> bool {
+    hash.len() == 32 && U256::from_big_endian(hash) <= target_from_bits(bits)
+}
+
+// Retargeting never goes easier than DIFFICULTY_BITS, so no valid block claims a higher target.
+pub fn within_limit(bits: u32) -> bool {
+    target_from_bits(bits) <= target_from_bits(DIFFICULTY_BITS)
+}
+
+// Expected number of hashes to find a block at this target, i.e. 2^256 / (target + 1).
+pub fn work_from_bits(bits: u32) -> U256 {
+    let target = target_from_bits(bits);
+    if target == U256::MAX {
+        return U256::one();
+    }
+
+    (!target / (target + U256::one())) + U256::one()
+}
+
+// Whether the block at `height` may record a different target than its parent.
+pub fn is_retarget_height(height: u32, config: &DifficultyConfig) -> bool {
+    let interval = config.retarget_interval.max(2);
+    height >= interval && height.is_multiple_of(interval)
+}
+
+// Target the block following `chain` must record. Only changes on retarget boundaries, scaling
+// the previous target by how long the last interval actually took, limited to a factor of 4.
+pub fn next_bits(chain: &[Block], config: &DifficultyConfig) -> u32 {
+    let last = match chain.last() {
+        Some(last) => last,
+        None => return DIFFICULTY_BITS,
+    };
+    let height = chain.len() as u32;
+    if !is_retarget_height(height, config) {
+        return last.header.difficulty_target;
+    }
+    let interval = config.retarget_interval.max(2);
+
+    let first = &chain[(height - interval) as usize];
+    let expected = (interval as i64 - 1) * config.target_block_time.max(1);
+    let actual = (last.header.timestamp - first.header.timestamp).clamp(expected / 4, expected * 4).max(1);
+
+    let target = target_from_bits(last.header.difficulty_target) / U256::from(expected) * U256::from(actual);
+    bits_from_target(target.min(target_from_bits(DIFFICULTY_BITS)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chain_with_block_time(len: u32, seconds: i64) -> Vec<Block> {
+        (0..len)
+            .map(|height| {
+                let mut block = Block::genesis_block();
+                block.header.height = height;
+                block.header.timestamp = 1_000 + height as i64 * seconds;
+                block
+            })
+            .collect()
+    }
+
+    #[test]
+    fn compact_bits_round_trip() {
+        for bits in [DIFFICULTY_BITS, 0x1d00ffff, 0x1b0404cb, 0x03123456] {
+            assert_eq!(bits_from_target(target_from_bits(bits)), bits);
+        }
+    }
+
+    #[test]
+    fn difficulty_bits_need_two_zero_bytes() {
+        let mut hash = [0u8; 32];
+        hash[1] = 1;
+        assert!(!meets_target(&hash, DIFFICULTY_BITS));
+
+        hash[1] = 0;
+        hash[2] = 0xff;
+        assert!(meets_target(&hash, DIFFICULTY_BITS));
+    }
+
+    #[test]
+    fn harder_targets_carry_more_work() {
+        assert_eq!(work_from_bits(DIFFICULTY_BITS), U256::from(65_537u64));
+        assert!(work_from_bits(0x1e00ffff) > work_from_bits(DIFFICULTY_BITS));
+
+        assert!(within_limit(0x1e00ffff));
+        assert!(!within_limit(0x2100ffff));
+    }
+
+    #[test]
+    fn retargets_only_on_interval_boundaries() {
+        let config = DifficultyConfig::default();
+        let fast = chain_with_block_time(config.retarget_interval - 1, 1);
+        assert_eq!(next_bits(&fast, &config), DIFFICULTY_BITS);
+
+        let fast = chain_with_block_time(config.retarget_interval, 1);
+        let harder = next_bits(&fast, &config);
+        assert!(target_from_bits(harder) < target_from_bits(DIFFICULTY_BITS));
+    }
+
+    #[test]
+    fn slow_blocks_ease_difficulty_but_not_past_the_limit() {
+        let config = DifficultyConfig::default();
+        let harder = next_bits(&chain_with_block_time(config.retarget_interval, 1), &config);
+
+        // twice as slow as intended, from an already harder target
+        let mut slow = chain_with_block_time(config.retarget_interval, config.target_block_time * 2);
+        slow.iter_mut().for_each(|block| block.header.difficulty_target = harder);
+        let eased = next_bits(&slow, &config);
+        assert!(target_from_bits(eased) > target_from_bits(harder));
+
+        let very_slow = chain_with_block_time(config.retarget_interval, config.target_block_time * 100);
+        assert_eq!(next_bits(&very_slow, &config), DIFFICULTY_BITS);
+    }
+}
@@ -0,0 +1,305 @@
+use super::blockchain::{App, Block, LedgerMode};
+use super::p2p::{self, AppBehaviour};
+use super::rest::{self, BlockView, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+use libp2p::swarm::Swarm;
+use log::error;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use serde::Serialize;
+use std::path::PathBuf;
+use tokio::sync::mpsc;
+
+pub const HISTORY_FILE: &str = "history"; // kept in the data dir
+
+// Usage and description of every command, for `help`, usage errors and tab completion.
+const COMMANDS: [(&str, &str); 11] = [
+    ("help", "list the commands"),
+    ("chain [start] [limit]", "list blocks, newest first, from height `start` down"),
+    ("show block <height|hash>", "print a block and its transactions"),
+    ("show tx <hash>", "print a transaction from the chain or the mempool"),
+    ("balance [address]", "balance and next nonce, of this node by default"),
+    ("send <recipient> <amount> [fee]", "transfer from this node; in UTXO mode this mines a block"),
+    ("peers", "list connected and discovered peers"),
+    ("dial <multiaddr>", "connect to a peer"),
+    ("mine", "mine a block now"),
+    ("mine start|stop", "turn periodic mining on or off"),
+    ("exit", "stop the node"),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Help,
+    Chain { start: Option<u32>, limit: u32 },
+    ShowBlock(String),
+    ShowTx(String),
+    Balance(Option<String>), // None for this node's own account
+    Send { recipient: String, amount: u64, fee: u64 },
+    Peers,
+    Dial(String),
+    Mine,
+    StartMining,
+    StopMining,
+    Exit,
+}
+
+pub fn parse(line: &str) -> Result<Command, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let command = match words.as_slice() {
+        ["help"] | ["?"] => Command::Help,
+        ["chain"] => Command::Chain { start: None, limit: DEFAULT_PAGE_SIZE },
+        ["chain", start] => Command::Chain { start: Some(number(start, "start")?), limit: DEFAULT_PAGE_SIZE },
+        ["chain", start, limit] => Command::Chain {
+            start: Some(number(start, "start")?),
+            limit: number::<u32>(limit, "limit")?.clamp(1, MAX_PAGE_SIZE),
+        },
+        ["show", "block", id] => Command::ShowBlock(id.to_string()),
+        ["show", "tx", hash] => Command::ShowTx(hash.to_string()),
+        ["balance"] => Command::Balance(None),
+        ["balance", address] => Command::Balance(Some(address.to_string())),
+        ["send", recipient, amount] => Command::Send {
+            recipient: recipient.to_string(),
+            amount: number(amount, "amount")?,
+            fee: 0,
+        },
+        ["send", recipient, amount, fee] => Command::Send {
+            recipient: recipient.to_string(),
+            amount: number(amount, "amount")?,
+            fee: number(fee, "fee")?,
+        },
+        ["peers"] => Command::Peers,
+        ["dial", addr] => Command::Dial(addr.to_string()),
+        ["mine"] => Command::Mine,
+        ["mine", "start"] => Command::StartMining,
+        ["mine", "stop"] => Command::StopMining,
+        ["exit"] | ["quit"] => Command::Exit,
+        [name, ..] => return Err(usage(name)),
+        [] => return Err(String::from("type `help` for the list of commands")),
+    };
+
+    Ok(command)
+}
+
+fn number<T: std::str::FromStr>(word: &str, name: &str) -> Result<T, String> {
+    word.parse().map_err(|_| format!("{} must be a number, not {}", name, word))
+}
+
+// The usage of a known command given the wrong arguments, or a pointer to `help`.
+fn usage(name: &str) -> String {
+    let usages: Vec<&str> = COMMANDS
+        .iter()
+        .map(|(usage, _)| *usage)
+        .filter(|usage| usage.split(' ').next() == Some(name))
+        .collect();
+
+    match usages.as_slice() {
+        [] => format!("unknown command {}, type `help` for the list of commands", name),
+        usages => format!("usage: {}", usages.join(" | ")),
+    }
+}
+
+// Candidates for the word before the cursor: a command name, or the subcommands of `show`
+// and `mine`. Returns where that word starts, for the editor to replace it.
+pub fn completions(line: &str) -> (usize, Vec<String>) {
+    let start = line.rfind(' ').map_or(0, |i| i + 1);
+    let (before, word) = line.split_at(start);
+
+    let mut options: Vec<&str> = match before.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [] => COMMANDS.iter().filter_map(|(usage, _)| usage.split(' ').next()).collect(),
+        ["show"] => vec!["block", "tx"],
+        ["mine"] => vec!["start", "stop"],
+        _ => vec![],
+    };
+    options.dedup();
+
+    (start, options.into_iter().filter(|option| option.starts_with(word)).map(String::from).collect())
+}
+
+struct ReplHelper;
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(completions(&line[..pos]))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+// The line editor blocks, so it gets a thread of its own and hands lines to the swarm loop.
+// Ctrl-C stops the node like `exit`; Ctrl-D, or the end of piped input, only stops reading.
+pub fn spawn(history: PathBuf, lines: mpsc::UnboundedSender<String>) {
+    std::thread::spawn(move || {
+        let mut editor = Editor::<ReplHelper>::new();
+        editor.set_helper(Some(ReplHelper));
+        // there's no history on the first run
+        let _ = editor.load_history(&history);
+
+        loop {
+            match editor.readline("> ") {
+                Ok(line) => {
+                    let line = line.trim();
+                    if line.is_empty() {
+                        continue;
+                    }
+                    editor.add_history_entry(line);
+                    if let Err(e) = editor.save_history(&history) {
+                        error!("can't save command history to {}: {}", history.display(), e);
+                    }
+                    if lines.send(line.to_string()).is_err() {
+                        break;
+                    }
+                }
+                Err(ReadlineError::Interrupted) => {
+                    let _ = lines.send(String::from("exit"));
+                    break;
+                }
+                Err(ReadlineError::Eof) => break,
+                Err(e) => {
+                    error!("can't read commands: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+}
+
+// Runs a command on the swarm loop. Returns false once the node should stop.
+pub fn handle(line: &str, swarm: &mut Swarm<AppBehaviour>, mining: &mut bool) -> bool {
+    let command = match parse(line) {
+        Ok(command) => command,
+        Err(e) => {
+            println!("{}", e);
+            return true;
+        }
+    };
+
+    let app = &swarm.behaviour().app;
+    match command {
+        Command::Help => {
+            for (usage, description) in COMMANDS {
+                println!("  {:<34}{}", usage, description);
+            }
+        }
+        Command::Chain { start, limit } => print_chain(app, start, limit),
+        Command::ShowBlock(id) => match rest::find_block(app, &id) {
+            Some(block) => print_json(&BlockView::new(block)),
+            None => println!("no block {}", id),
+        },
+        Command::ShowTx(hash) => match rest::find_transaction(app, &hash) {
+            Some(tx) => print_json(&tx),
+            None => println!("no transaction {}", hash),
+        },
+        Command::Balance(address) => {
+            let address = address.unwrap_or_else(|| p2p::PEER_ID.to_string());
+            println!("{}: balance {}, next nonce {}", address, app.balance(&address), app.next_nonce(&address));
+        }
+        Command::Send { recipient, amount, fee } => send(swarm.behaviour_mut(), recipient, amount, fee),
+        Command::Peers => {
+            let peers = p2p::get_list_peers(swarm);
+            if peers.is_empty() {
+                println!("no peers");
+            }
+            peers.iter().for_each(|peer| println!("{}", peer));
+        }
+        Command::Dial(addr) => p2p::dial(swarm, &addr),
+        Command::Mine => {
+            println!("mining a block");
+            let reply = Box::new(|block: Option<&Block>| match block {
+                Some(block) => println!("mined block {} {}", block.header.height, block.hash),
+                None => println!("mined block did not extend the chain"),
+            });
+            p2p::create_block(swarm.behaviour_mut(), None, reply);
+        }
+        Command::StartMining => {
+            *mining = true;
+            println!("periodic mining started");
+        }
+        Command::StopMining => {
+            *mining = false;
+            println!("periodic mining stopped");
+        }
+        Command::Exit => return false,
+    }
+
+    true
+}
+
+fn print_json<T: Serialize>(value: &T) {
+    println!("{}", serde_json::to_string_pretty(value).expect("can jsonify view"));
+}
+
+fn print_chain(app: &App, start: Option<u32>, limit: u32) {
+    let page = rest::block_page(app, start, limit);
+    for block in &page.blocks {
+        println!("{:>8}  {}  {}  {} txs", block.height, block.hash, block.timestamp, block.transactions.len());
+    }
+
+    match page.next {
+        Some(next) => println!("{} of {} blocks, `chain {} {}` for older ones", page.blocks.len(), page.total, next, limit),
+        None => println!("{} of {} blocks", page.blocks.len(), page.total),
+    }
+}
+
+// Account transfers go through the mempool. A UTXO transfer can only be mined into a block of
+// our own, as there's no pool for UTXO transactions.
+fn send(behaviour: &mut AppBehaviour, recipient: String, amount: u64, fee: u64) {
+    match behaviour.app.mode {
+        LedgerMode::Account => {
+            if let Some(tx) = p2p::submit_transfer(&mut behaviour.app, recipient, amount, fee) {
+                behaviour.publish_transaction(&tx);
+                println!("sent transaction {}", tx.hash());
+            }
+        }
+        LedgerMode::Utxo => {
+            let reply = Box::new(|block: Option<&Block>| match block {
+                Some(block) => println!("mined the transfer into block {} {}", block.header.height, block.hash),
+                None => println!("the block with the transfer did not extend the chain, send it again"),
+            });
+            p2p::create_block(behaviour, Some((recipient, amount, fee)), reply);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_parse() {
+        assert_eq!(parse("  show   block 12 "), Ok(Command::ShowBlock(String::from("12"))));
+        assert_eq!(parse("chain"), Ok(Command::Chain { start: None, limit: DEFAULT_PAGE_SIZE }));
+        assert_eq!(parse("chain 40 1000"), Ok(Command::Chain { start: Some(40), limit: MAX_PAGE_SIZE }));
+        assert_eq!(
+            parse("send bob 5 1"),
+            Ok(Command::Send { recipient: String::from("bob"), amount: 5, fee: 1 })
+        );
+        assert_eq!(parse("balance"), Ok(Command::Balance(None)));
+        assert_eq!(parse("mine stop"), Ok(Command::StopMining));
+
+        assert_eq!(parse("send bob five"), Err(String::from("amount must be a number, not five")));
+        assert_eq!(parse("mine now"), Err(String::from("usage: mine | mine start|stop")));
+        assert!(parse("ls c").unwrap_err().contains("help"));
+    }
+
+    #[test]
+    fn words_complete() {
+        assert_eq!(completions("s"), (0, vec![String::from("show"), String::from("send")]));
+        assert_eq!(completions("mi"), (0, vec![String::from("mine")]));
+        assert_eq!(completions("show "), (5, vec![String::from("block"), String::from("tx")]));
+        assert_eq!(completions("mine st"), (5, vec![String::from("start"), String::from("stop")]));
+        assert_eq!(completions("send bo"), (5, vec![]));
+    }
+}
@@ -0,0 +1,354 @@
+use super::blockchain::{App, Block, Transaction};
+use super::http::{self, Request, Response};
+use super::merkle::{self, MerkleProof, ProofStep};
+use super::p2p::{self, AppBehaviour};
+use super::utxo::UtxoTransaction;
+use libp2p::swarm::Swarm;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+pub const DEFAULT_PAGE_SIZE: u32 = 20;
+pub const MAX_PAGE_SIZE: u32 = 100;
+
+// The explorer schema. These are kept apart from the wire types, so the chain's internals can
+// change without breaking anything reading from the API.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BlockView {
+    pub hash: String,
+    pub height: u32,
+    pub prev_hash: String,
+    pub merkle_root: String,
+    pub timestamp: i64,
+    pub difficulty_target: u32,
+    pub nonce: u64,
+    pub transactions: Vec<TransactionView>,
+}
+
+// Account transfers and UTXO transactions share one shape: a transfer has no inputs and a
+// single output, a UTXO transaction has no sender, nonce or fee.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TransactionView {
+    pub hash: String,
+    pub block_hash: Option<String>, // None while pending
+    pub block_height: Option<u32>,
+    pub coinbase: bool,
+    pub sender: Option<String>,
+    pub nonce: Option<u64>,
+    pub fee: Option<u64>,
+    pub inputs: Vec<InputView>,
+    pub outputs: Vec<OutputView>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct InputView {
+    pub tx_hash: String,
+    pub index: u32,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct OutputView {
+    pub recipient: String,
+    pub amount: u64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AccountView {
+    pub address: String,
+    pub balance: u64,
+    pub nonce: u64, // nonce for the next transaction, pending ones included
+    pub pending_transactions: usize,
+}
+
+// Newest block first. `next` is the `start` of the following page, if there is one.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BlockPage {
+    pub blocks: Vec<BlockView>,
+    pub total: usize,
+    pub next: Option<u32>,
+}
+
+// Inclusion of a transaction in an active chain block: hashing `steps` up from the leaf has to
+// give the `merkle_root` in that block's header.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProofView {
+    pub block_hash: String,
+    pub block_height: u32,
+    pub merkle_root: String,
+    pub index: usize,
+    pub steps: Vec<ProofStep>,
+}
+
+impl BlockView {
+    pub fn new(block: &Block) -> BlockView {
+        let transactions = block
+            .transactions
+            .iter()
+            .map(TransactionView::transfer)
+            .chain(block.utxo_transactions.iter().map(TransactionView::utxo))
+            .map(|tx| tx.in_block(block))
+            .collect();
+
+        BlockView {
+            hash: block.hash.clone(),
+            height: block.header.height,
+            prev_hash: block.header.prev_hash.clone(),
+            merkle_root: block.header.merkle_root.clone(),
+            timestamp: block.header.timestamp,
+            difficulty_target: block.header.difficulty_target,
+            nonce: block.header.nonce,
+            transactions,
+        }
+    }
+}
+
+impl TransactionView {
+    pub fn transfer(tx: &Transaction) -> TransactionView {
+        let coinbase = tx.is_coinbase();
+        TransactionView {
+            hash: tx.hash(),
+            block_hash: None,
+            block_height: None,
+            coinbase,
+            sender: if coinbase { None } else { Some(tx.sender.clone()) },
+            nonce: if coinbase { None } else { Some(tx.nonce) },
+            fee: if coinbase { None } else { Some(tx.fee) },
+            inputs: vec![],
+            outputs: vec![OutputView { recipient: tx.recipient.clone(), amount: tx.amount }],
+        }
+    }
+
+    pub fn utxo(tx: &UtxoTransaction) -> TransactionView {
+        TransactionView {
+            hash: tx.hash(),
+            block_hash: None,
+            block_height: None,
+            coinbase: tx.is_coinbase(),
+            sender: None,
+            nonce: None,
+            fee: None,
+            inputs: tx
+                .inputs
+                .iter()
+                .map(|input| InputView { tx_hash: input.prev_out.tx_hash.clone(), index: input.prev_out.index })
+                .collect(),
+            outputs: tx
+                .outputs
+                .iter()
+                .map(|output| OutputView { recipient: output.recipient.clone(), amount: output.amount })
+                .collect(),
+        }
+    }
+
+    fn in_block(mut self, block: &Block) -> TransactionView {
+        self.block_hash = Some(block.hash.clone());
+        self.block_height = Some(block.header.height);
+        self
+    }
+}
+
+pub type RestCall = http::Call<Request, Response>;
+
+pub async fn serve(listener: TcpListener, calls: mpsc::UnboundedSender<RestCall>) {
+    http::serve(listener, move |request| answer(request, calls.clone())).await
+}
+
+async fn answer(request: Request, calls: mpsc::UnboundedSender<RestCall>) -> Response {
+    http::Call::send(&calls, request).await.unwrap_or_else(|reason| error(500, reason))
+}
+
+pub fn handle(call: RestCall, swarm: &Swarm<AppBehaviour>) {
+    let response = route(&call.request, &swarm.behaviour().app, || p2p::get_list_peers(swarm));
+    call.answer(response);
+}
+
+fn error(status: u16, message: &str) -> Response {
+    Response::json(status, &json!({ "error": message }))
+}
+
+fn route(request: &Request, app: &App, peers: impl FnOnce() -> Vec<String>) -> Response {
+    if request.method != "GET" {
+        return error(405, "the API is read-only");
+    }
+
+    let (path, query) = match request.path.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (request.path.as_str(), ""),
+    };
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    match segments[..] {
+        ["blocks"] => match page_params(query) {
+            Ok((start, limit)) => Response::json(200, &block_page(app, start, limit)),
+            Err(message) => error(400, &message),
+        },
+        ["blocks", id] => match find_block(app, id) {
+            Some(block) => Response::json(200, &BlockView::new(block)),
+            None => error(404, "no such block"),
+        },
+        ["tx", hash] => match find_transaction(app, hash) {
+            Some(tx) => Response::json(200, &tx),
+            None => error(404, "no such transaction"),
+        },
+        ["tx", hash, "proof"] => match find_proof(app, hash) {
+            Some(proof) => Response::json(200, &proof),
+            None => error(404, "no such transaction in the chain"),
+        },
+        ["accounts", address] => Response::json(
+            200,
+            &AccountView {
+                address: address.to_string(),
+                balance: app.balance(address),
+                nonce: app.next_nonce(address),
+                pending_transactions: app.mempool.pending_count(address),
+            },
+        ),
+        ["peers"] => Response::json(200, &json!({ "peers": peers() })),
+        _ => error(404, "no such resource"),
+    }
+}
+
+// `?start=<height>&limit=<n>`; start defaults to the tip.
+fn page_params(query: &str) -> Result<(Option<u32>, u32), String> {
+    let mut start = None;
+    let mut limit = DEFAULT_PAGE_SIZE;
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let value: u32 = value.parse().map_err(|_| format!("{} must be a number", key))?;
+        match key {
+            "start" => start = Some(value),
+            "limit" => limit = value.clamp(1, MAX_PAGE_SIZE),
+            _ => return Err(format!("unknown parameter {}", key)),
+        }
+    }
+
+    Ok((start, limit))
+}
+
+pub fn block_page(app: &App, start: Option<u32>, limit: u32) -> BlockPage {
+    let blocks = app.blocks();
+    let tip = blocks.len() as u32 - 1;
+    let start = start.unwrap_or(tip).min(tip);
+    let end = start.saturating_sub(limit - 1);
+
+    BlockPage {
+        blocks: (end..=start).rev().map(|height| BlockView::new(&blocks[height as usize])).collect(),
+        total: blocks.len(),
+        next: end.checked_sub(1),
+    }
+}
+
+// Blocks are addressed by height, or by hash for blocks outside the active chain.
+pub fn find_block<'a>(app: &'a App, id: &str) -> Option<&'a Block> {
+    match id.parse::<u32>() {
+        Ok(height) => app.blocks().get(height as usize),
+        Err(_) => app.tree.get(id),
+    }
+}
+
+// Looks in the active chain, then the mempool.
+pub fn find_transaction(app: &App, hash: &str) -> Option<TransactionView> {
+    if let Some((block, index)) = app.locate_transaction(hash) {
+        // positions count account transactions first, as in the merkle tree
+        let tx = match block.transactions.get(index) {
+            Some(tx) => TransactionView::transfer(tx),
+            None => TransactionView::utxo(block.utxo_transactions.get(index - block.transactions.len())?),
+        };
+        return Some(tx.in_block(block));
+    }
+
+    app.mempool.get(hash).map(TransactionView::transfer)
+}
+
+// Only transactions in the active chain have a proof, pending ones aren't in any block yet.
+pub fn find_proof(app: &App, hash: &str) -> Option<ProofView> {
+    let (block, _) = app.locate_transaction(hash)?;
+    let proof = block.prove_transaction(hash)?;
+    Some(ProofView {
+        block_hash: block.hash.clone(),
+        block_height: block.header.height,
+        merkle_root: block.header.merkle_root.clone(),
+        index: proof.index,
+        steps: proof.steps,
+    })
+}
+
+// Holds if `hash` hashes up to the proof's root and that root is in the named active chain block.
+pub fn check_proof(app: &App, hash: &str, proof: &ProofView) -> bool {
+    let in_chain = app.blocks().get(proof.block_height as usize).is_some_and(|block| {
+        block.hash == proof.block_hash && block.header.merkle_root == proof.merkle_root
+    });
+    let steps = MerkleProof { leaf: hash.to_string(), index: proof.index, steps: proof.steps.clone() };
+    in_chain && merkle::verify_proof(&proof.merkle_root, &steps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::tests::app_with_blocks;
+    use crate::blockchain::BLOCK_REWARD;
+
+    fn get(path: &str) -> Request {
+        Request { method: String::from("GET"), path: path.to_string(), headers: vec![], body: vec![] }
+    }
+
+    fn body(response: &Response) -> serde_json::Value {
+        serde_json::from_slice(&response.body).expect("json body")
+    }
+
+    #[test]
+    fn blocks_are_paginated_newest_first() {
+        let app = app_with_blocks(4);
+        let peers = Vec::new;
+
+        let page = body(&route(&get("/blocks?limit=2"), &app, peers));
+        let heights: Vec<u64> = page["blocks"].as_array().expect("blocks").iter().map(|b| b["height"].as_u64().expect("height")).collect();
+        assert_eq!(heights, vec![4, 3]);
+        assert_eq!(page["total"], json!(5));
+        assert_eq!(page["next"], json!(2));
+
+        let last = body(&route(&get("/blocks?start=1&limit=5"), &app, peers));
+        assert_eq!(last["blocks"].as_array().expect("blocks").len(), 2);
+        assert_eq!(last["next"], json!(null));
+
+        assert_eq!(route(&get("/blocks?limit=many"), &app, peers).status, 400);
+    }
+
+    #[test]
+    fn blocks_transactions_and_accounts_are_found() {
+        let app = app_with_blocks(1);
+        let block = app.latest_block().clone();
+        let coinbase = &block.transactions[0];
+
+        let found = body(&route(&get("/blocks/1"), &app, Vec::new));
+        assert_eq!(found["hash"], json!(block.hash));
+        assert_eq!(body(&route(&get(&format!("/blocks/{}", block.hash)), &app, Vec::new))["height"], json!(1));
+        assert_eq!(route(&get("/blocks/7"), &app, Vec::new).status, 404);
+
+        let tx = body(&route(&get(&format!("/tx/{}", coinbase.hash())), &app, Vec::new));
+        assert_eq!(tx["block_height"], json!(1));
+        assert_eq!(tx["coinbase"], json!(true));
+        assert_eq!(tx["outputs"], json!([{ "recipient": "miner", "amount": BLOCK_REWARD }]));
+
+        let proof = body(&route(&get(&format!("/tx/{}/proof", coinbase.hash())), &app, Vec::new));
+        assert_eq!(proof["block_hash"], json!(block.hash));
+        assert_eq!(proof["merkle_root"], json!(block.header.merkle_root));
+        assert_eq!(proof["index"], json!(0));
+        assert_eq!(route(&get("/tx/missing/proof"), &app, Vec::new).status, 404);
+
+        let mut proof = find_proof(&app, &coinbase.hash()).expect("coinbase is in the chain");
+        assert!(check_proof(&app, &coinbase.hash(), &proof));
+        assert!(!check_proof(&app, "missing", &proof));
+        proof.block_height = 0;
+        assert!(!check_proof(&app, &coinbase.hash(), &proof), "the root has to be in the named block");
+
+        let account = body(&route(&get("/accounts/miner"), &app, Vec::new));
+        assert_eq!(account["balance"], json!(BLOCK_REWARD));
+
+        let peers = body(&route(&get("/peers"), &app, || vec![String::from("peer")]));
+        assert_eq!(peers, json!({ "peers": ["peer"] }));
+        let post = Request { method: String::from("POST"), ..get("/peers") };
+        assert_eq!(route(&post, &app, Vec::new).status, 405);
+    }
+}
@@ -0,0 +1,226 @@
+use super::blockchain::{Block, LedgerMode, Transaction};
+use super::http::{self, Request, Response};
+use super::p2p::{self, AppBehaviour};
+use super::rest;
+use libp2p::swarm::Swarm;
+use log::info;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+const REJECTED: i64 = -32000; // the node refused the call, e.g. an invalid transaction
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct RpcRequest {
+    pub method: String,
+    #[serde(default)]
+    pub params: Value, // positional array or named object
+    #[serde(default)]
+    pub id: Value,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: impl Into<String>) -> RpcError {
+        RpcError { code, message: message.into() }
+    }
+}
+
+pub type RpcCall = http::Call<RpcRequest, Result<Value, RpcError>>;
+
+pub fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(error) => json!({ "jsonrpc": "2.0", "error": error, "id": id }),
+    }
+}
+
+pub fn parse(body: &[u8]) -> Result<RpcRequest, RpcError> {
+    let value: Value = serde_json::from_slice(body).map_err(|e| RpcError::new(PARSE_ERROR, e.to_string()))?;
+    serde_json::from_value(value).map_err(|e| RpcError::new(INVALID_REQUEST, e.to_string()))
+}
+
+// Reads parameter `name`, passed either by position or by name.
+pub fn param<T: DeserializeOwned>(params: &Value, index: usize, name: &str) -> Result<T, RpcError> {
+    let value = lookup(params, index, name)
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("missing parameter {}", name)))?;
+    serde_json::from_value(value.clone()).map_err(|e| RpcError::new(INVALID_PARAMS, format!("invalid {}: {}", name, e)))
+}
+
+// Like `param`, but a missing or null parameter is None.
+pub fn optional_param<T: DeserializeOwned>(params: &Value, index: usize, name: &str) -> Result<Option<T>, RpcError> {
+    match lookup(params, index, name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(_) => param(params, index, name).map(Some),
+    }
+}
+
+fn lookup<'a>(params: &'a Value, index: usize, name: &str) -> Option<&'a Value> {
+    match params {
+        Value::Array(values) => values.get(index),
+        Value::Object(fields) => fields.get(name),
+        _ => None,
+    }
+}
+
+// Sends the request to the swarm loop and waits for its answer.
+pub async fn call(request: RpcRequest, calls: &mpsc::UnboundedSender<RpcCall>) -> Result<Value, RpcError> {
+    http::Call::send(calls, request)
+        .await
+        .unwrap_or_else(|reason| Err(RpcError::new(INTERNAL_ERROR, reason)))
+}
+
+pub async fn serve(listener: TcpListener, calls: mpsc::UnboundedSender<RpcCall>) {
+    http::serve(listener, move |request| answer(request, calls.clone())).await
+}
+
+async fn answer(request: Request, calls: mpsc::UnboundedSender<RpcCall>) -> Response {
+    if request.method != "POST" {
+        return Response::json(405, &response(Value::Null, Err(RpcError::new(INVALID_REQUEST, "requests must be POSTed"))));
+    }
+
+    match parse(&request.body) {
+        Ok(request) => {
+            let id = request.id.clone();
+            Response::json(200, &response(id, call(request, &calls).await))
+        }
+        Err(error) => Response::json(200, &response(Value::Null, Err(error))),
+    }
+}
+
+// Calls `method` on the node serving JSON-RPC at `addr`, for command line clients.
+pub async fn request(addr: &str, method: &str, params: Value) -> Result<Value, String> {
+    let body = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+    let (_, body) = http::post_json(addr, "/", &body)
+        .await
+        .map_err(|e| format!("can't reach the node at {}: {}", addr, e))?;
+    let mut response: Value = serde_json::from_slice(&body).map_err(|e| format!("invalid response: {}", e))?;
+
+    match response.get("error") {
+        Some(error) => Err(format!("{} failed: {}", method, error["message"].as_str().unwrap_or("unknown error"))),
+        None => Ok(response["result"].take()),
+    }
+}
+
+// Runs on the swarm loop, so methods can read the chain and publish to peers directly.
+pub fn handle(call: RpcCall, swarm: &mut Swarm<AppBehaviour>) {
+    info!("RPC call {}", call.request.method);
+    if call.request.method == "mineBlock" {
+        // answered once the block is found, the loop doesn't wait for it
+        let reply = Box::new(move |block: Option<&Block>| call.answer(mined(block)));
+        p2p::create_block(swarm.behaviour_mut(), None, reply);
+        return;
+    }
+
+    let result = dispatch(&call.request, swarm);
+    call.answer(result);
+}
+
+fn dispatch(request: &RpcRequest, swarm: &mut Swarm<AppBehaviour>) -> Result<Value, RpcError> {
+    let params = &request.params;
+    let app = &swarm.behaviour().app;
+
+    match request.method.as_str() {
+        "getTip" => {
+            let tip = app.latest_block();
+            Ok(json!({ "height": tip.header.height, "hash": tip.hash }))
+        }
+        "getBlockByHeight" => {
+            let height: u32 = param(params, 0, "height")?;
+            Ok(json!(app.blocks().get(height as usize)))
+        }
+        "getBlockByHash" => {
+            let hash: String = param(params, 0, "hash")?;
+            Ok(json!(app.tree.get(&hash)))
+        }
+        "getBalance" => {
+            let address: String = param(params, 0, "address")?;
+            match optional_param::<u32>(params, 1, "height")? {
+                Some(height) => {
+                    let balance = app
+                        .balance_at(&address, height)
+                        .ok_or_else(|| RpcError::new(REJECTED, format!("no block at height {}", height)))?;
+                    Ok(json!({ "address": address, "height": height, "balance": balance }))
+                }
+                None => Ok(json!({ "address": address, "balance": app.balance(&address), "nonce": app.next_nonce(&address) })),
+            }
+        }
+        "getProof" => {
+            let hash: String = param(params, 0, "hash")?;
+            let proof = rest::find_proof(app, &hash)
+                .ok_or_else(|| RpcError::new(REJECTED, format!("no transaction {} in the chain", hash)))?;
+            Ok(json!(proof))
+        }
+        "verifyProof" => {
+            let hash: String = param(params, 0, "hash")?;
+            let proof: rest::ProofView = param(params, 1, "proof")?;
+            Ok(json!(rest::check_proof(app, &hash, &proof)))
+        }
+        "getPeers" => Ok(json!(p2p::get_list_peers(swarm))),
+        "submitTransaction" => {
+            if app.mode != LedgerMode::Account {
+                return Err(RpcError::new(REJECTED, "transactions can only be submitted in account mode"));
+            }
+            let tx: Transaction = param(params, 0, "transaction")?;
+            let behaviour = swarm.behaviour_mut();
+            let hash = behaviour
+                .app
+                .submit_transaction(tx.clone())
+                .map_err(|e| RpcError::new(REJECTED, e.to_string()))?;
+            behaviour.publish_transaction(&tx);
+            Ok(json!(hash))
+        }
+        method => Err(RpcError::new(METHOD_NOT_FOUND, format!("unknown method {}", method))),
+    }
+}
+
+fn mined(block: Option<&Block>) -> Result<Value, RpcError> {
+    match block {
+        Some(block) => Ok(json!({ "height": block.header.height, "hash": block.hash })),
+        None => Err(RpcError::new(REJECTED, "mined block did not extend the chain")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn params_are_read_by_position_or_name() {
+        let request = parse(br#"{"jsonrpc": "2.0", "method": "getBlockByHeight", "params": [3], "id": 1}"#)
+            .expect("valid request");
+        assert_eq!(param::<u32>(&request.params, 0, "height"), Ok(3));
+
+        let named = json!({ "height": 4 });
+        assert_eq!(param::<u32>(&named, 0, "height"), Ok(4));
+        assert_eq!(param::<u32>(&json!(["four"]), 0, "height").map_err(|e| e.code), Err(INVALID_PARAMS));
+        assert_eq!(param::<u32>(&Value::Null, 0, "height").map_err(|e| e.code), Err(INVALID_PARAMS));
+
+        assert_eq!(optional_param::<u32>(&json!(["alice"]), 1, "height"), Ok(None));
+        assert_eq!(optional_param::<u32>(&json!(["alice", null]), 1, "height"), Ok(None));
+        assert_eq!(optional_param::<u32>(&json!({ "height": 2 }), 1, "height"), Ok(Some(2)));
+        assert!(optional_param::<u32>(&json!(["alice", "two"]), 1, "height").is_err());
+    }
+
+    #[test]
+    fn malformed_requests_get_standard_errors() {
+        assert_eq!(parse(b"{").map_err(|e| e.code), Err(PARSE_ERROR));
+        assert_eq!(parse(br#"{"params": []}"#).map_err(|e| e.code), Err(INVALID_REQUEST));
+
+        let error = response(json!(7), Err(RpcError::new(METHOD_NOT_FOUND, "unknown method foo")));
+        assert_eq!(error["error"]["code"], json!(METHOD_NOT_FOUND));
+        assert_eq!(error["id"], json!(7));
+        assert_eq!(response(json!(7), Ok(Value::Null)).get("result"), Some(&Value::Null));
+    }
+}
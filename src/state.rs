@@ -0,0 +1,206 @@
+use super::blockchain::{Block, BLOCK_REWARD};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Account {
+    pub balance: u64,
+    pub nonce: u64, // nonce expected on the next transaction from this account
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StateError {
+    Overspend { sender: String, balance: u64, required: u64 },
+    BadNonce { sender: String, expected: u64, got: u64 },
+    BadCoinbase { expected: u64, got: u64 },
+    CoinbaseHeight { expected: u32, got: u64 },
+    Overflow, // amounts add up to more than a u64 holds
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::Overspend { sender, balance, required } => {
+                write!(f, "{} spends {} but only holds {}", sender, required, balance)
+            }
+            StateError::BadNonce { sender, expected, got } => {
+                write!(f, "{} used nonce {}, expected {}", sender, got, expected)
+            }
+            StateError::BadCoinbase { expected, got } => {
+                write!(f, "coinbase pays {}, expected {}", got, expected)
+            }
+            StateError::CoinbaseHeight { expected, got } => {
+                write!(f, "coinbase is for height {}, expected {}", got, expected)
+            }
+            StateError::Overflow => write!(f, "amounts overflow"),
+        }
+    }
+}
+
+// World state: account balances and nonces obtained by replaying the chain.
+#[derive(Debug, Clone, Default)]
+pub struct State {
+    accounts: HashMap<String, Account>,
+}
+
+impl State {
+    pub fn new() -> State {
+        State::default()
+    }
+
+    #[cfg(test)]
+    pub fn from_chain(blocks: &[Block]) -> Result<State, StateError> {
+        let mut state = State::new();
+        for block in blocks {
+            state.apply_block(block)?;
+        }
+
+        Ok(state)
+    }
+
+    pub fn account(&self, address: &str) -> Account {
+        self.accounts.get(address).cloned().unwrap_or_default()
+    }
+
+    pub fn balance(&self, address: &str) -> u64 {
+        self.account(address).balance
+    }
+
+    pub fn nonce(&self, address: &str) -> u64 {
+        self.account(address).nonce
+    }
+
+    // Applies every transaction in the block, or none of them if any is invalid.
+    pub fn apply_block(&mut self, block: &Block) -> Result<(), StateError> {
+        let mut next = self.clone();
+
+        let fees = block
+            .transactions
+            .iter()
+            .filter(|tx| !tx.is_coinbase())
+            .try_fold(0u64, |fees, tx| fees.checked_add(tx.fee))
+            .ok_or(StateError::Overflow)?;
+        let reward = BLOCK_REWARD.checked_add(fees).ok_or(StateError::Overflow)?;
+
+        for tx in &block.transactions {
+            if tx.is_coinbase() {
+                if tx.amount != reward {
+                    return Err(StateError::BadCoinbase { expected: reward, got: tx.amount });
+                }
+                // its nonce is the height, so no two coinbases share a hash
+                if tx.nonce != block.header.height as u64 {
+                    return Err(StateError::CoinbaseHeight { expected: block.header.height, got: tx.nonce });
+                }
+                next.credit(&tx.recipient, tx.amount)?;
+                continue;
+            }
+
+            let sender = next.accounts.entry(tx.sender.clone()).or_default();
+            if tx.nonce != sender.nonce {
+                return Err(StateError::BadNonce {
+                    sender: tx.sender.clone(),
+                    expected: sender.nonce,
+                    got: tx.nonce,
+                });
+            }
+            let required = tx.amount.saturating_add(tx.fee);
+            if sender.balance < required {
+                return Err(StateError::Overspend {
+                    sender: tx.sender.clone(),
+                    balance: sender.balance,
+                    required,
+                });
+            }
+            sender.balance -= required;
+            sender.nonce += 1;
+
+            next.credit(&tx.recipient, tx.amount)?;
+        }
+
+        *self = next;
+        Ok(())
+    }
+
+    fn credit(&mut self, address: &str, amount: u64) -> Result<(), StateError> {
+        let account = self.accounts.entry(address.to_string()).or_default();
+        account.balance = account.balance.checked_add(amount).ok_or(StateError::Overflow)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Transaction;
+
+    fn block(height: u32, transactions: Vec<Transaction>) -> Block {
+        let mut block = Block::genesis_block();
+        block.header.height = height;
+        block.transactions = transactions;
+        block
+    }
+
+    #[test]
+    fn replays_transfers_into_balances() {
+        let blocks = vec![
+            block(1, vec![Transaction::coinbase(String::from("alice"), BLOCK_REWARD, 1)]),
+            block(2, vec![
+                Transaction::coinbase(String::from("bob"), BLOCK_REWARD + 1, 2),
+                Transaction::new(String::from("alice"), String::from("carol"), 10, 1, 0),
+            ]),
+        ];
+
+        let state = State::from_chain(&blocks).expect("chain is valid");
+        assert_eq!(state.balance("alice"), BLOCK_REWARD - 11);
+        assert_eq!(state.balance("carol"), 10);
+        assert_eq!(state.balance("bob"), BLOCK_REWARD + 1);
+        assert_eq!(state.nonce("alice"), 1);
+    }
+
+    #[test]
+    fn rejects_overspend_and_replayed_nonce() {
+        let mut state = State::new();
+        state
+            .apply_block(&block(1, vec![Transaction::coinbase(String::from("alice"), BLOCK_REWARD, 1)]))
+            .expect("coinbase applies");
+
+        let overspend = Transaction::new(String::from("alice"), String::from("bob"), BLOCK_REWARD + 1, 0, 0);
+        assert!(matches!(
+            state.apply_block(&block(2, vec![overspend])),
+            Err(StateError::Overspend { .. })
+        ));
+
+        let first = Transaction::new(String::from("alice"), String::from("bob"), 5, 0, 0);
+        state.apply_block(&block(2, vec![first.clone()])).expect("transfer applies");
+        assert!(matches!(
+            state.apply_block(&block(3, vec![first])),
+            Err(StateError::BadNonce { .. })
+        ));
+        assert_eq!(state.balance("bob"), 5);
+    }
+
+    #[test]
+    fn rejects_coinbase_for_another_height() {
+        let mut state = State::new();
+        let replayed = Transaction::coinbase(String::from("miner"), BLOCK_REWARD, 1);
+        state.apply_block(&block(1, vec![replayed.clone()])).expect("coinbase applies");
+
+        assert_eq!(
+            state.apply_block(&block(2, vec![replayed])),
+            Err(StateError::CoinbaseHeight { expected: 2, got: 1 })
+        );
+        assert_eq!(state.balance("miner"), BLOCK_REWARD);
+    }
+
+    #[test]
+    fn rejects_fees_that_overflow() {
+        let mut state = State::new();
+        let fees = vec![
+            Transaction::coinbase(String::from("miner"), BLOCK_REWARD, 1),
+            Transaction::new(String::from("alice"), String::from("bob"), 1, u64::MAX, 0),
+            Transaction::new(String::from("carol"), String::from("bob"), 1, 2, 0),
+        ];
+        assert_eq!(state.apply_block(&block(1, fees)), Err(StateError::Overflow));
+        assert_eq!(state.balance("miner"), 0);
+    }
+}
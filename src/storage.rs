@@ -0,0 +1,366 @@
+use super::blockchain::Block;
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const BLOCKS_FILE: &str = "blocks.dat";
+const INDEX_FILE: &str = "blocks.idx";
+
+// Where `App` keeps its blocks: the active chain, plus every block it ever accepted (side
+// branches included) so the block tree can be rebuilt on restart.
+pub trait ChainStore: Send {
+    // Active chain, genesis first.
+    fn chain(&self) -> &[Block];
+
+    // The chain is updated even when writing fails; the error only means the block wasn't kept.
+    fn push(&mut self, block: Block) -> io::Result<()>;
+    fn set_chain(&mut self, blocks: Vec<Block>) -> io::Result<()>;
+
+    // Keeps a block that isn't on the active chain.
+    fn store(&mut self, block: &Block) -> io::Result<()>;
+
+    // Every kept block, in the order it was stored.
+    fn stored_blocks(&mut self) -> io::Result<Vec<Block>>;
+}
+
+// Keeps everything in memory and forgets it on exit.
+#[derive(Default)]
+pub struct MemoryStore {
+    chain: Vec<Block>,
+    blocks: Vec<Block>,
+    hashes: HashSet<String>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl ChainStore for MemoryStore {
+    fn chain(&self) -> &[Block] {
+        &self.chain
+    }
+
+    fn push(&mut self, block: Block) -> io::Result<()> {
+        self.store(&block)?;
+        self.chain.push(block);
+        Ok(())
+    }
+
+    fn set_chain(&mut self, blocks: Vec<Block>) -> io::Result<()> {
+        for block in &blocks {
+            self.store(block)?;
+        }
+        self.chain = blocks;
+        Ok(())
+    }
+
+    fn store(&mut self, block: &Block) -> io::Result<()> {
+        if self.hashes.insert(block.hash.clone()) {
+            self.blocks.push(block.clone());
+        }
+        Ok(())
+    }
+
+    fn stored_blocks(&mut self) -> io::Result<Vec<Block>> {
+        Ok(self.blocks.clone())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexEntry {
+    pub height: u32,
+    pub offset: u64, // start of the record, i.e. of its length prefix
+    pub length: u32, // length of the JSON body
+}
+
+// Append-only block file in `dir`. Each record is a little-endian u32 length followed by the
+// block as JSON. The index file has one `hash height offset length` line per record, so single
+// blocks can be read without scanning; it's rebuilt from the block file when it falls behind.
+pub struct BlockStore {
+    dir: PathBuf,
+    blocks: File,
+    index: File,
+    entries: HashMap<String, IndexEntry>,
+    order: Vec<String>, // hashes in the order they were appended
+    end: u64,
+    chain: Vec<Block>, // active chain, rebuilt by `App` replaying the stored blocks
+}
+
+impl BlockStore {
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<BlockStore> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let blocks = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.join(BLOCKS_FILE))?;
+        let index = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.join(INDEX_FILE))?;
+
+        let mut store = BlockStore {
+            dir,
+            blocks,
+            index,
+            entries: HashMap::new(),
+            order: vec![],
+            end: 0,
+            chain: vec![],
+        };
+        store.load_index()?;
+        Ok(store)
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn append(&mut self, block: &Block) -> io::Result<()> {
+        if self.contains(&block.hash) {
+            return Ok(());
+        }
+
+        let body = serde_json::to_vec(block)?;
+        let entry = IndexEntry {
+            height: block.header.height,
+            offset: self.end,
+            length: body.len() as u32,
+        };
+
+        let mut record = Vec::with_capacity(4 + body.len());
+        record.extend_from_slice(&entry.length.to_le_bytes());
+        record.extend_from_slice(&body);
+        self.blocks.write_all(&record)?;
+        self.blocks.sync_data()?;
+
+        writeln!(self.index, "{} {} {} {}", block.hash, entry.height, entry.offset, entry.length)?;
+
+        self.end += record.len() as u64;
+        self.order.push(block.hash.clone());
+        self.entries.insert(block.hash.clone(), entry);
+        Ok(())
+    }
+
+    pub fn get(&mut self, hash: &str) -> io::Result<Option<Block>> {
+        let entry = match self.entries.get(hash) {
+            Some(entry) => entry.clone(),
+            None => return Ok(None),
+        };
+
+        let mut body = vec![0u8; entry.length as usize];
+        self.blocks.seek(SeekFrom::Start(entry.offset + 4))?;
+        self.blocks.read_exact(&mut body)?;
+        Ok(Some(serde_json::from_slice(&body)?))
+    }
+
+    // Every stored block, in the order it was appended.
+    pub fn load_blocks(&mut self) -> io::Result<Vec<Block>> {
+        let hashes = self.order.clone();
+        let mut blocks = Vec::with_capacity(hashes.len());
+        for hash in hashes {
+            if let Some(block) = self.get(&hash)? {
+                blocks.push(block);
+            }
+        }
+
+        Ok(blocks)
+    }
+
+    fn load_index(&mut self) -> io::Result<()> {
+        let file_len = self.blocks.metadata()?.len();
+
+        self.index.seek(SeekFrom::Start(0))?;
+        let lines: Vec<String> = BufReader::new(&self.index).lines().collect::<io::Result<_>>()?;
+        for line in lines {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let entry = match fields.as_slice() {
+                [hash, height, offset, length] => match (height.parse(), offset.parse(), length.parse()) {
+                    (Ok(height), Ok(offset), Ok(length)) => (hash.to_string(), IndexEntry { height, offset, length }),
+                    _ => break,
+                },
+                _ => break,
+            };
+            self.insert_entry(entry.0, entry.1);
+        }
+
+        if self.end != file_len {
+            warn!("Block index is out of date, rebuilding it from {}", BLOCKS_FILE);
+            self.rebuild_index(file_len)?;
+        }
+
+        info!("Opened block store with {} blocks in {}", self.order.len(), self.dir.display());
+        Ok(())
+    }
+
+    fn insert_entry(&mut self, hash: String, entry: IndexEntry) {
+        self.end = entry.offset + 4 + entry.length as u64;
+        self.order.push(hash.clone());
+        self.entries.insert(hash, entry);
+    }
+
+    // Scans the block file record by record. A record cut short by a crash is truncated away.
+    fn rebuild_index(&mut self, file_len: u64) -> io::Result<()> {
+        self.entries.clear();
+        self.order.clear();
+        self.end = 0;
+
+        let mut reader = BufReader::new(&self.blocks);
+        reader.seek(SeekFrom::Start(0))?;
+        let mut rebuilt = vec![];
+        let mut offset = 0;
+        loop {
+            let mut length = [0u8; 4];
+            if reader.read_exact(&mut length).is_err() {
+                break;
+            }
+            let length = u32::from_le_bytes(length);
+            let mut body = vec![0u8; length as usize];
+            if reader.read_exact(&mut body).is_err() {
+                break;
+            }
+            let block: Block = match serde_json::from_slice(&body) {
+                Ok(block) => block,
+                Err(_) => break,
+            };
+
+            rebuilt.push((block.hash, IndexEntry { height: block.header.height, offset, length }));
+            offset += 4 + length as u64;
+        }
+
+        if offset != file_len {
+            warn!("Dropping {} bytes of incomplete block data", file_len - offset);
+            self.blocks.set_len(offset)?;
+        }
+
+        let mut index = File::create(self.dir.join(INDEX_FILE))?;
+        for (hash, entry) in rebuilt {
+            writeln!(index, "{} {} {} {}", hash, entry.height, entry.offset, entry.length)?;
+            self.insert_entry(hash, entry);
+        }
+        index.sync_data()?;
+        self.index = OpenOptions::new().read(true).append(true).open(self.dir.join(INDEX_FILE))?;
+
+        Ok(())
+    }
+}
+
+impl ChainStore for BlockStore {
+    fn chain(&self) -> &[Block] {
+        &self.chain
+    }
+
+    fn push(&mut self, block: Block) -> io::Result<()> {
+        let result = self.append(&block);
+        self.chain.push(block);
+        result
+    }
+
+    fn set_chain(&mut self, blocks: Vec<Block>) -> io::Result<()> {
+        let result = blocks.iter().try_for_each(|block| self.append(block));
+        self.chain = blocks;
+        result
+    }
+
+    fn store(&mut self, block: &Block) -> io::Result<()> {
+        self.append(block)
+    }
+
+    fn stored_blocks(&mut self) -> io::Result<Vec<Block>> {
+        self.load_blocks()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{App, BlockStatus, LedgerMode, Transaction, BLOCK_REWARD};
+    use crate::pow::DifficultyConfig;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("blockchain-store-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn block(height: u32) -> Block {
+        let mut block = Block::genesis_block();
+        block.header.height = height;
+        block.hash = block.header.hash();
+        block
+    }
+
+    #[test]
+    fn blocks_survive_reopening() {
+        let dir = temp_dir("reopen");
+        {
+            let mut store = BlockStore::open(&dir).expect("can open store");
+            store.append(&block(0)).expect("can append");
+            store.append(&block(1)).expect("can append");
+            store.append(&block(1)).expect("duplicates are ignored");
+        }
+
+        let mut store = BlockStore::open(&dir).expect("can reopen store");
+        let blocks = store.load_blocks().expect("can load blocks");
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[1].hash, block(1).hash);
+        assert!(store.get(&block(0).hash).expect("can read").is_some());
+
+        fs::remove_dir_all(&dir).expect("can clean up");
+    }
+
+    #[test]
+    fn index_is_rebuilt_and_torn_writes_dropped() {
+        let dir = temp_dir("rebuild");
+        {
+            let mut store = BlockStore::open(&dir).expect("can open store");
+            store.append(&block(0)).expect("can append");
+            store.append(&block(1)).expect("can append");
+        }
+        fs::remove_file(dir.join(INDEX_FILE)).expect("can remove index");
+        let mut blocks = OpenOptions::new().append(true).open(dir.join(BLOCKS_FILE)).expect("can open");
+        blocks.write_all(&[200, 0, 0, 0, b'{']).expect("can write partial record");
+
+        let mut store = BlockStore::open(&dir).expect("can reopen store");
+        assert_eq!(store.len(), 2);
+        store.append(&block(2)).expect("can append after recovery");
+        assert_eq!(BlockStore::open(&dir).expect("can reopen").load_blocks().expect("can load").len(), 3);
+
+        fs::remove_dir_all(&dir).expect("can clean up");
+    }
+
+    #[test]
+    fn app_reloads_its_chain_from_the_store() {
+        let dir = temp_dir("app");
+        let tip = {
+            let store = BlockStore::open(&dir).expect("can open store");
+            let mut app = App::with_store(LedgerMode::Account, DifficultyConfig::default(), Box::new(store)).expect("can load app");
+            let block = Block::new(
+                app.latest_block(),
+                app.next_difficulty_target(),
+                vec![Transaction::coinbase(String::from("miner"), BLOCK_REWARD, 1)],
+            );
+            assert_eq!(app.add_block_to_chain(block.clone()), BlockStatus::Extended);
+            block.hash
+        };
+
+        let store = BlockStore::open(&dir).expect("can reopen store");
+        let app = App::with_store(LedgerMode::Account, DifficultyConfig::default(), Box::new(store)).expect("can reload app");
+        assert_eq!(app.latest_block().hash, tip);
+        assert_eq!(app.balance("miner"), BLOCK_REWARD);
+
+        fs::remove_dir_all(&dir).expect("can clean up");
+    }
+}
@@ -0,0 +1,119 @@
+use super::wire::{self, SyncRequest, SyncResponse};
+use async_trait::async_trait;
+use libp2p::core::upgrade::{read_length_prefixed, write_length_prefixed, ProtocolName};
+use libp2p::futures::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use libp2p::request_response::{
+    ProtocolSupport, RequestResponse, RequestResponseCodec, RequestResponseConfig,
+};
+use std::io;
+use std::iter;
+
+// Far above a full header or block batch; a peer must not make us buffer arbitrary amounts.
+const MAX_SYNC_MESSAGE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct SyncProtocol;
+
+impl ProtocolName for SyncProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/blockchain/sync/2"
+    }
+}
+
+// Each request and response is one length-prefixed wire frame.
+#[derive(Clone, Default)]
+pub struct SyncCodec;
+
+fn invalid_data(e: wire::WireError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+#[async_trait]
+impl RequestResponseCodec for SyncCodec {
+    type Protocol = SyncProtocol;
+    type Request = SyncRequest;
+    type Response = SyncResponse;
+
+    async fn read_request<T>(&mut self, _: &SyncProtocol, io: &mut T) -> io::Result<SyncRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let frame = read_length_prefixed(io, MAX_SYNC_MESSAGE).await?;
+        wire::decode(&frame).map_err(invalid_data)
+    }
+
+    async fn read_response<T>(&mut self, _: &SyncProtocol, io: &mut T) -> io::Result<SyncResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let frame = read_length_prefixed(io, MAX_SYNC_MESSAGE).await?;
+        wire::decode(&frame).map_err(invalid_data)
+    }
+
+    async fn write_request<T>(&mut self, _: &SyncProtocol, io: &mut T, req: SyncRequest) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, wire::encode(&req)).await?;
+        io.close().await
+    }
+
+    async fn write_response<T>(&mut self, _: &SyncProtocol, io: &mut T, res: SyncResponse) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, wire::encode(&res)).await?;
+        io.close().await
+    }
+}
+
+pub fn new_behaviour() -> RequestResponse<SyncCodec> {
+    RequestResponse::new(
+        SyncCodec,
+        iter::once((SyncProtocol, ProtocolSupport::Full)),
+        RequestResponseConfig::default(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Block;
+    use crate::wire::Message;
+    use libp2p::futures::executor::block_on;
+    use libp2p::futures::io::Cursor;
+
+    #[test]
+    fn requests_and_responses_round_trip() {
+        block_on(async {
+            let request = SyncRequest::Headers { locator: vec![Block::genesis_block().hash] };
+            let mut io = Cursor::new(vec![]);
+            SyncCodec.write_request(&SyncProtocol, &mut io, request.clone()).await.expect("can write request");
+            io.set_position(0);
+            assert_eq!(SyncCodec.read_request(&SyncProtocol, &mut io).await.expect("can read request"), request);
+
+            let response = SyncResponse::Blocks(vec![Block::genesis_block()]);
+            let mut io = Cursor::new(vec![]);
+            SyncCodec.write_response(&SyncProtocol, &mut io, response.clone()).await.expect("can write response");
+            io.set_position(0);
+            assert_eq!(SyncCodec.read_response(&SyncProtocol, &mut io).await.expect("can read response"), response);
+        });
+    }
+
+    #[test]
+    fn oversized_and_foreign_frames_are_refused() {
+        block_on(async {
+            let mut io = Cursor::new(vec![]);
+            write_length_prefixed(&mut io, vec![0; MAX_SYNC_MESSAGE + 1]).await.expect("can write frame");
+            io.set_position(0);
+            assert!(SyncCodec.read_request(&SyncProtocol, &mut io).await.is_err());
+
+            // a gossip message where a sync request belongs
+            let mut io = Cursor::new(vec![]);
+            write_length_prefixed(&mut io, wire::encode(&Message::Block(Block::genesis_block()))).await.expect("can write frame");
+            io.set_position(0);
+            let e = SyncCodec.read_request(&SyncProtocol, &mut io).await.expect_err("not a request");
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        });
+    }
+}
@@ -0,0 +1,344 @@
+use super::blockchain::{verify_signed_by, Block, BLOCK_REWARD};
+use libp2p::{identity, PeerId};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct OutPoint {
+    pub tx_hash: String,
+    pub index: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TxInput {
+    pub prev_out: OutPoint,
+    pub public_key: String, // must derive the address that owns `prev_out`
+    pub signature: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TxOutput {
+    pub recipient: String,
+    pub amount: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UtxoTransaction {
+    pub inputs: Vec<TxInput>,
+    pub outputs: Vec<TxOutput>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coinbase_height: Option<u32>, // only set on the reward transaction, keeps its hash unique
+}
+
+#[derive(Debug, PartialEq)]
+pub enum UtxoError {
+    MissingInput(OutPoint),
+    DoubleSpend(OutPoint),
+    BadSignature(OutPoint),
+    OutputsExceedInputs { inputs: u64, outputs: u64 },
+    BadCoinbase { expected: u64, got: u64 },
+    Overflow, // amounts add up to more than a u64 holds
+}
+
+impl fmt::Display for UtxoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UtxoError::MissingInput(out) => write!(f, "input {}:{} is not unspent", out.tx_hash, out.index),
+            UtxoError::DoubleSpend(out) => write!(f, "input {}:{} spent twice in block", out.tx_hash, out.index),
+            UtxoError::BadSignature(out) => write!(f, "bad signature spending {}:{}", out.tx_hash, out.index),
+            UtxoError::OutputsExceedInputs { inputs, outputs } => {
+                write!(f, "outputs {} exceed inputs {}", outputs, inputs)
+            }
+            UtxoError::BadCoinbase { expected, got } => write!(f, "coinbase pays {}, expected {}", got, expected),
+            UtxoError::Overflow => write!(f, "amounts overflow"),
+        }
+    }
+}
+
+impl UtxoTransaction {
+    pub fn coinbase(recipient: String, amount: u64, height: u32) -> UtxoTransaction {
+        UtxoTransaction {
+            inputs: vec![],
+            outputs: vec![TxOutput { recipient, amount }],
+            coinbase_height: Some(height),
+        }
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    pub fn hash(&self) -> String {
+        let data = serde_json::to_string(self).expect("can jsonify transaction");
+
+        let mut hasher = Sha256::new();
+        hasher.update(data.as_bytes());
+        hex::encode(hasher.finalize().as_slice())
+    }
+
+    // Every input signs the spent outpoints and all outputs, so nothing can be swapped afterwards.
+    fn signing_payload(&self) -> Vec<u8> {
+        let prev_outs: Vec<&OutPoint> = self.inputs.iter().map(|input| &input.prev_out).collect();
+        serde_json::json!({
+            "inputs": prev_outs,
+            "outputs": self.outputs,
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    pub fn sign(&mut self, keys: &identity::Keypair) {
+        let payload = self.signing_payload();
+        let public_key = hex::encode(keys.public().into_protobuf_encoding());
+        let signature = hex::encode(keys.sign(&payload).expect("can sign transaction"));
+
+        for input in self.inputs.iter_mut() {
+            input.public_key = public_key.clone();
+            input.signature = signature.clone();
+        }
+    }
+
+    // None if the outputs add up to more than a u64 holds.
+    pub fn total_output(&self) -> Option<u64> {
+        self.outputs.iter().try_fold(0u64, |total, out| total.checked_add(out.amount))
+    }
+}
+
+// Unspent outputs, indexed by outpoint and by owning address.
+#[derive(Debug, Clone, Default)]
+pub struct UtxoSet {
+    unspent: HashMap<OutPoint, TxOutput>,
+    by_address: HashMap<String, HashSet<OutPoint>>,
+}
+
+impl UtxoSet {
+    pub fn new() -> UtxoSet {
+        UtxoSet::default()
+    }
+
+    #[cfg(test)]
+    pub fn from_chain(blocks: &[Block]) -> Result<UtxoSet, UtxoError> {
+        let mut utxos = UtxoSet::new();
+        for block in blocks {
+            utxos.apply_block(block)?;
+        }
+
+        Ok(utxos)
+    }
+
+    pub fn outputs_for(&self, address: &str) -> Vec<(OutPoint, TxOutput)> {
+        self.by_address
+            .get(address)
+            .into_iter()
+            .flatten()
+            .map(|out| (out.clone(), self.unspent[out].clone()))
+            .collect()
+    }
+
+    pub fn balance(&self, address: &str) -> u64 {
+        self.outputs_for(address).iter().map(|(_, out)| out.amount).sum()
+    }
+
+    fn insert(&mut self, outpoint: OutPoint, output: TxOutput) {
+        self.by_address
+            .entry(output.recipient.clone())
+            .or_default()
+            .insert(outpoint.clone());
+        self.unspent.insert(outpoint, output);
+    }
+
+    fn remove(&mut self, outpoint: &OutPoint) -> Option<TxOutput> {
+        let output = self.unspent.remove(outpoint)?;
+        if let Some(outs) = self.by_address.get_mut(&output.recipient) {
+            outs.remove(outpoint);
+            if outs.is_empty() {
+                self.by_address.remove(&output.recipient);
+            }
+        }
+
+        Some(output)
+    }
+
+    fn add_outputs(&mut self, tx: &UtxoTransaction) {
+        let tx_hash = tx.hash();
+        for (index, output) in tx.outputs.iter().enumerate() {
+            let outpoint = OutPoint { tx_hash: tx_hash.clone(), index: index as u32 };
+            self.insert(outpoint, output.clone());
+        }
+    }
+
+    // Spends and creates every output in the block, or changes nothing if any transaction is invalid.
+    pub fn apply_block(&mut self, block: &Block) -> Result<(), UtxoError> {
+        let mut next = self.clone();
+        let mut spent_in_block = HashSet::new();
+        let mut fees: u64 = 0;
+
+        for tx in block.utxo_transactions.iter().filter(|tx| !tx.is_coinbase()) {
+            let payload = tx.signing_payload();
+            let mut total_input: u64 = 0;
+
+            for input in &tx.inputs {
+                if !spent_in_block.insert(input.prev_out.clone()) {
+                    return Err(UtxoError::DoubleSpend(input.prev_out.clone()));
+                }
+                let output = next
+                    .remove(&input.prev_out)
+                    .ok_or_else(|| UtxoError::MissingInput(input.prev_out.clone()))?;
+                if !verify_signed_by(&input.public_key, &input.signature, &payload, &output.recipient) {
+                    return Err(UtxoError::BadSignature(input.prev_out.clone()));
+                }
+                total_input = total_input.checked_add(output.amount).ok_or(UtxoError::Overflow)?;
+            }
+
+            let total_output = tx.total_output().ok_or(UtxoError::Overflow)?;
+            let fee = total_input
+                .checked_sub(total_output)
+                .ok_or(UtxoError::OutputsExceedInputs { inputs: total_input, outputs: total_output })?;
+            fees = fees.checked_add(fee).ok_or(UtxoError::Overflow)?;
+            next.add_outputs(tx);
+        }
+
+        // coinbase outputs only become spendable in later blocks
+        let expected = BLOCK_REWARD.checked_add(fees).ok_or(UtxoError::Overflow)?;
+        for tx in block.utxo_transactions.iter().filter(|tx| tx.is_coinbase()) {
+            let got = tx.total_output().ok_or(UtxoError::Overflow)?;
+            if got != expected {
+                return Err(UtxoError::BadCoinbase { expected, got });
+            }
+            next.add_outputs(tx);
+        }
+
+        *self = next;
+        Ok(())
+    }
+
+    // Greedy coin selection over the keypair's outputs, returning change to the sender.
+    pub fn create_transaction(
+        &self,
+        keys: &identity::Keypair,
+        recipient: String,
+        amount: u64,
+        fee: u64,
+    ) -> Option<UtxoTransaction> {
+        let sender = PeerId::from(keys.public()).to_string();
+        let required = amount.checked_add(fee)?;
+
+        let mut inputs = vec![];
+        let mut total = 0;
+        for (outpoint, output) in self.outputs_for(&sender) {
+            if total >= required {
+                break;
+            }
+            total += output.amount;
+            inputs.push(TxInput {
+                prev_out: outpoint,
+                public_key: String::new(),
+                signature: String::new(),
+            });
+        }
+        if total < required {
+            return None;
+        }
+
+        let mut outputs = vec![TxOutput { recipient, amount }];
+        if total > required {
+            outputs.push(TxOutput { recipient: sender, amount: total - required });
+        }
+
+        let mut tx = UtxoTransaction { inputs, outputs, coinbase_height: None };
+        tx.sign(keys);
+        Some(tx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(height: u32, utxo_transactions: Vec<UtxoTransaction>) -> Block {
+        let mut block = Block::genesis_block();
+        block.header.height = height;
+        block.utxo_transactions = utxo_transactions;
+        block
+    }
+
+    fn funded(keys: &identity::Keypair) -> (UtxoSet, UtxoTransaction) {
+        let owner = PeerId::from(keys.public()).to_string();
+        let reward = UtxoTransaction::coinbase(owner, BLOCK_REWARD, 1);
+        let utxos = UtxoSet::from_chain(&[block(1, vec![reward.clone()])]).expect("coinbase applies");
+        (utxos, reward)
+    }
+
+    #[test]
+    fn spends_outputs_and_returns_change() {
+        let keys = identity::Keypair::generate_ed25519();
+        let owner = PeerId::from(keys.public()).to_string();
+        let (mut utxos, _) = funded(&keys);
+
+        let tx = utxos
+            .create_transaction(&keys, String::from("bob"), 20, 1)
+            .expect("enough funds");
+        let reward = UtxoTransaction::coinbase(String::from("miner"), BLOCK_REWARD + 1, 2);
+        utxos.apply_block(&block(2, vec![reward, tx])).expect("transfer applies");
+
+        assert_eq!(utxos.balance("bob"), 20);
+        assert_eq!(utxos.balance(&owner), BLOCK_REWARD - 21);
+        assert_eq!(utxos.balance("miner"), BLOCK_REWARD + 1);
+    }
+
+    #[test]
+    fn rejects_double_spends() {
+        let keys = identity::Keypair::generate_ed25519();
+        let (mut utxos, _) = funded(&keys);
+
+        let first = utxos.create_transaction(&keys, String::from("bob"), 5, 0).expect("enough funds");
+        let second = utxos.create_transaction(&keys, String::from("carol"), 5, 0).expect("enough funds");
+        assert!(matches!(
+            utxos.apply_block(&block(2, vec![first.clone(), second.clone()])),
+            Err(UtxoError::DoubleSpend(_))
+        ));
+
+        utxos.apply_block(&block(2, vec![first])).expect("first spend applies");
+        assert!(matches!(
+            utxos.apply_block(&block(3, vec![second])),
+            Err(UtxoError::MissingInput(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_spending_someone_elses_output() {
+        let keys = identity::Keypair::generate_ed25519();
+        let thief = identity::Keypair::generate_ed25519();
+        let (mut utxos, _) = funded(&keys);
+
+        let mut tx = utxos.create_transaction(&keys, String::from("bob"), 5, 0).expect("enough funds");
+        tx.sign(&thief);
+        assert!(matches!(
+            utxos.apply_block(&block(2, vec![tx])),
+            Err(UtxoError::BadSignature(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_outputs_that_overflow() {
+        let keys = identity::Keypair::generate_ed25519();
+        let (mut utxos, _) = funded(&keys);
+
+        // wrapping, these would add up to 1 and leave a fee the coinbase could claim
+        let mut tx = utxos.create_transaction(&keys, String::from("bob"), 1, 0).expect("enough funds");
+        tx.outputs = vec![
+            TxOutput { recipient: String::from("bob"), amount: u64::MAX },
+            TxOutput { recipient: String::from("carol"), amount: 2 },
+        ];
+        tx.sign(&keys);
+        assert_eq!(tx.total_output(), None);
+        assert_eq!(utxos.apply_block(&block(2, vec![tx])), Err(UtxoError::Overflow));
+
+        let mut reward = UtxoTransaction::coinbase(String::from("miner"), u64::MAX, 2);
+        reward.outputs.push(TxOutput { recipient: String::from("miner"), amount: BLOCK_REWARD + 1 });
+        assert_eq!(utxos.apply_block(&block(2, vec![reward])), Err(UtxoError::Overflow));
+        assert_eq!(utxos.balance("bob"), 0);
+    }
+}
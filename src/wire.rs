@@ -0,0 +1,152 @@
+use super::blockchain::{Block, BlockHeader, Transaction};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fmt;
+
+// Bumped whenever a message type changes incompatibly. Frames start with this byte.
+pub const PROTOCOL_VERSION: u8 = 4;
+
+// Everything peers broadcast over gossipsub. Encoded as CBOR, so the variant name
+// travels with the payload and nothing has to be guessed from its shape.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Message {
+    Block(Block),
+    Transaction(Transaction),
+}
+
+// Sent point to point over the sync protocol, answered by a `SyncResponse`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum SyncRequest {
+    Tip,
+    Headers { locator: Vec<String> }, // active chain hashes, see `App::locator`
+    Blocks(Vec<String>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum SyncResponse {
+    Tip { height: u32, hash: String },
+    Headers(#[serde(with = "packed_headers")] Vec<BlockHeader>),
+    Blocks(Vec<Block>), // the requested blocks we have, in request order
+}
+
+// Headers go back to back in their fixed 92 byte layout, see `BlockHeader::to_bytes`, rather
+// than as maps of hex strings. A full batch is a third of the size.
+mod packed_headers {
+    use crate::blockchain::{BlockHeader, HEADER_SIZE};
+    use serde::de::{self, Visitor};
+    use serde::{Deserializer, Serializer};
+    use std::fmt;
+
+    pub fn serialize<S: Serializer>(headers: &[BlockHeader], serializer: S) -> Result<S::Ok, S::Error> {
+        let bytes: Vec<u8> = headers.iter().flat_map(|header| header.to_bytes()).collect();
+        serializer.serialize_bytes(&bytes)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<BlockHeader>, D::Error> {
+        deserializer.deserialize_bytes(PackedHeaders)
+    }
+
+    struct PackedHeaders;
+
+    impl<'de> Visitor<'de> for PackedHeaders {
+        type Value = Vec<BlockHeader>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "headers of {} bytes each", HEADER_SIZE)
+        }
+
+        fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Vec<BlockHeader>, E> {
+            if !bytes.len().is_multiple_of(HEADER_SIZE) {
+                return Err(E::invalid_length(bytes.len(), &self));
+            }
+            Ok(bytes.chunks(HEADER_SIZE).filter_map(BlockHeader::from_bytes).collect())
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum WireError {
+    Empty,
+    UnsupportedVersion(u8),
+    Malformed(String),
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WireError::Empty => write!(f, "empty message"),
+            WireError::UnsupportedVersion(version) => {
+                write!(f, "protocol version {} is not supported, expected {}", version, PROTOCOL_VERSION)
+            }
+            WireError::Malformed(reason) => write!(f, "malformed message: {}", reason),
+        }
+    }
+}
+
+pub fn encode<T: Serialize>(message: &T) -> Vec<u8> {
+    let mut frame = vec![PROTOCOL_VERSION];
+    frame.extend(serde_cbor::to_vec(message).expect("can encode message"));
+    frame
+}
+
+pub fn decode<T: DeserializeOwned>(frame: &[u8]) -> Result<T, WireError> {
+    let (version, body) = frame.split_first().ok_or(WireError::Empty)?;
+    if *version != PROTOCOL_VERSION {
+        return Err(WireError::UnsupportedVersion(*version));
+    }
+
+    serde_cbor::from_slice(body).map_err(|e| WireError::Malformed(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::HEADER_SIZE;
+
+    #[test]
+    fn messages_round_trip() {
+        let messages = vec![
+            Message::Block(Block::genesis_block()),
+            Message::Transaction(Transaction::new(String::from("alice"), String::from("bob"), 5, 1, 0)),
+        ];
+
+        for message in messages {
+            let frame = encode(&message);
+            assert_eq!(frame[0], PROTOCOL_VERSION);
+            assert_eq!(decode(&frame), Ok(message));
+        }
+
+        let mut next = Block::genesis_block().header;
+        next.height = 1;
+        next.prev_hash = Block::genesis_block().hash;
+        let response = SyncResponse::Headers(vec![Block::genesis_block().header, next]);
+        let frame = encode(&response);
+        assert!(frame.len() < 2 * HEADER_SIZE + 16, "headers are packed, got {} bytes", frame.len());
+        assert_eq!(decode(&frame), Ok(response));
+
+        // a truncated header
+        let packed = serde_cbor::to_vec(&serde_cbor::Value::Map(
+            [(serde_cbor::Value::Text(String::from("Headers")), serde_cbor::Value::Bytes(vec![0; HEADER_SIZE - 1]))]
+                .into_iter()
+                .collect(),
+        ))
+        .expect("can encode cbor");
+        let mut frame = vec![PROTOCOL_VERSION];
+        frame.extend(packed);
+        assert!(matches!(decode::<SyncResponse>(&frame), Err(WireError::Malformed(_))));
+        assert!(decode::<SyncRequest>(&encode(&Message::Block(Block::genesis_block()))).is_err());
+    }
+
+    #[test]
+    fn unknown_versions_and_payloads_are_rejected() {
+        let mut frame = encode(&Message::Block(Block::genesis_block()));
+        frame[0] = PROTOCOL_VERSION + 1;
+        assert_eq!(decode::<Message>(&frame), Err(WireError::UnsupportedVersion(PROTOCOL_VERSION + 1)));
+
+        assert_eq!(decode::<Message>(&[]), Err(WireError::Empty));
+        assert!(matches!(decode::<Message>(&[PROTOCOL_VERSION, b'{', b'}']), Err(WireError::Malformed(_))));
+
+        // a JSON encoded block, as older nodes sent them
+        let json = serde_json::to_vec(&Block::genesis_block()).expect("can jsonify block");
+        assert!(decode::<Message>(&json).is_err());
+    }
+}
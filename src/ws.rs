@@ -0,0 +1,126 @@
+use super::events::{ChainEvent, TOPICS};
+use super::rpc::{self, RpcError, INVALID_PARAMS, METHOD_NOT_FOUND};
+use libp2p::futures::{SinkExt, StreamExt};
+use log::{error, info};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
+use tokio::{select, spawn};
+use tokio_tungstenite::tungstenite::{self, Message};
+
+const FEED_CAPACITY: usize = 1024; // events a slow subscriber may fall behind before it misses some
+
+// Pushes chain events to WebSocket clients. Clients pick topics with JSON-RPC requests,
+// `{"method": "subscribe", "params": ["newHeads"]}`, and then receive notifications of the form
+// `{"method": "subscription", "params": {"subscription": "newHeads", "result": <event>}}`.
+pub async fn serve(listener: TcpListener, mut events: mpsc::UnboundedReceiver<ChainEvent>) {
+    let (feed, _) = broadcast::channel(FEED_CAPACITY);
+    let sender = feed.clone();
+    spawn(async move {
+        while let Some(event) = events.recv().await {
+            // fails only while nobody is connected
+            let _ = sender.send(event);
+        }
+    });
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                let events = feed.subscribe();
+                spawn(async move {
+                    info!("WebSocket subscriber connected from {}", addr);
+                    if let Err(e) = handle_connection(stream, events).await {
+                        error!("WebSocket connection from {} failed: {}", addr, e);
+                    }
+                });
+            }
+            Err(e) => error!("can't accept WebSocket connection: {}", e),
+        }
+    }
+}
+
+async fn handle_connection(stream: TcpStream, mut events: broadcast::Receiver<ChainEvent>) -> tungstenite::Result<()> {
+    let mut socket = tokio_tungstenite::accept_async(stream).await?;
+    let mut topics = HashSet::new();
+
+    loop {
+        select! {
+            message = socket.next() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    let reply = handle_request(&text, &mut topics);
+                    socket.send(Message::Text(reply.to_string())).await?;
+                }
+                Some(Ok(Message::Close(_))) | None => return Ok(()),
+                Some(Ok(_)) => {} // pings are answered by tungstenite
+                Some(Err(e)) => return Err(e),
+            },
+            event = events.recv() => match event {
+                Ok(event) if topics.contains(event.topic()) => {
+                    socket.send(Message::Text(notification(&event).to_string())).await?;
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(missed)) => error!("WebSocket subscriber fell behind, {} events dropped", missed),
+                Err(RecvError::Closed) => return Ok(()),
+            },
+        }
+    }
+}
+
+fn handle_request(text: &str, topics: &mut HashSet<String>) -> Value {
+    let request = match rpc::parse(text.as_bytes()) {
+        Ok(request) => request,
+        Err(error) => return rpc::response(Value::Null, Err(error)),
+    };
+
+    let result = match request.method.as_str() {
+        "subscribe" => rpc::param::<String>(&request.params, 0, "topic").and_then(|topic| {
+            if !TOPICS.contains(&topic.as_str()) {
+                return Err(RpcError::new(INVALID_PARAMS, format!("unknown topic {}, expected one of {:?}", topic, TOPICS)));
+            }
+            topics.insert(topic.clone());
+            Ok(json!(topic))
+        }),
+        "unsubscribe" => rpc::param::<String>(&request.params, 0, "topic").map(|topic| json!(topics.remove(&topic))),
+        method => Err(RpcError::new(METHOD_NOT_FOUND, format!("unknown method {}", method))),
+    };
+    rpc::response(request.id, result)
+}
+
+fn notification(event: &ChainEvent) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "subscription",
+        "params": { "subscription": event.topic(), "result": event },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clients_choose_their_topics() {
+        let mut topics = HashSet::new();
+
+        let reply = handle_request(r#"{"jsonrpc": "2.0", "id": 1, "method": "subscribe", "params": ["newHeads"]}"#, &mut topics);
+        assert_eq!(reply["result"], json!("newHeads"));
+        assert!(topics.contains("newHeads"));
+
+        let reply = handle_request(r#"{"id": 2, "method": "subscribe", "params": ["blocks"]}"#, &mut topics);
+        assert_eq!(reply["error"]["code"], json!(INVALID_PARAMS));
+
+        let reply = handle_request(r#"{"id": 3, "method": "unsubscribe", "params": {"topic": "newHeads"}}"#, &mut topics);
+        assert_eq!(reply["result"], json!(true));
+        assert!(topics.is_empty());
+    }
+
+    #[test]
+    fn notifications_name_their_topic() {
+        let event = ChainEvent::PendingTransaction { hash: String::from("abc") };
+        let message = notification(&event);
+        assert_eq!(message["params"]["subscription"], json!("newPendingTransactions"));
+        assert_eq!(message["params"]["result"], json!({ "type": "PendingTransaction", "hash": "abc" }));
+    }
+}